@@ -0,0 +1,100 @@
+use crate::client_profile::DEFAULT_PROFILE_NAME;
+use crate::error_page;
+use crate::stats::Stats;
+use crate::the_insecure_proxy::TheInsecureProxy;
+
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::body::Incoming;
+use hyper::{Method, Request, Response, StatusCode, Uri};
+use std::fmt::Write;
+
+// Answers requests on admin listeners: a health check for load balancers and
+// orchestrators to poll, and counters for Prometheus to scrape.
+pub fn handle<B>(req: &Request<B>, stats: &Stats) -> Response<Full<Bytes>> {
+    match (req.method(), req.uri().path()) {
+        (&Method::GET | &Method::HEAD, "/" | "/health") => {
+            Response::new(Full::new(Bytes::from_static(b"ok\n")))
+        }
+        (&Method::GET | &Method::HEAD, "/metrics") => {
+            Response::new(Full::new(Bytes::from(stats.render())))
+        }
+        _ => error_page::response(StatusCode::NOT_FOUND, "Not found"),
+    }
+}
+
+// Fetches the URLs POSTed to /prewarm, one per line, into the cache - for
+// filling the disk cache before going somewhere with a poor connection. Takes
+// a ?profile= to fetch them as, and answers with how each one went.
+pub async fn prewarm(proxy: &TheInsecureProxy, req: Request<Incoming>) -> Response<Full<Bytes>> {
+    if req.method() != Method::POST {
+        return error_page::response(
+            StatusCode::METHOD_NOT_ALLOWED,
+            "POST a list of URLs, one per line",
+        );
+    }
+    let profile = req
+        .uri()
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .find_map(|param| param.strip_prefix("profile="))
+        .unwrap_or(DEFAULT_PROFILE_NAME)
+        .to_string();
+    if !proxy.has_profile(&profile) {
+        return error_page::response(StatusCode::BAD_REQUEST, "There's no profile by that name");
+    }
+    let body = match req.into_body().collect().await {
+        Ok(body) => body.to_bytes(),
+        Err(_) => return error_page::response(StatusCode::BAD_REQUEST, "Couldn't read the list"),
+    };
+
+    let mut report = String::new();
+    let urls = String::from_utf8_lossy(&body);
+    let urls = urls
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'));
+    for url in urls {
+        let outcome = match url.parse::<Uri>() {
+            Ok(uri) => proxy.prewarm(&profile, &uri).await,
+            Err(err) => Err(err.into()),
+        };
+        match outcome {
+            Ok(status) => {
+                let _ = writeln!(report, "{} {}", status.as_u16(), url);
+            }
+            Err(err) => {
+                println!("= Couldn't pre-warm {}: {}", url, err);
+                let _ = writeln!(report, "failed {} ({})", url, err);
+            }
+        }
+    }
+    Response::new(Full::new(Bytes::from(report)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get(path: &str) -> StatusCode {
+        let req = Request::get(path).body(()).unwrap();
+        handle(&req, &Stats::default()).status()
+    }
+
+    #[test]
+    fn health_check() {
+        assert_eq!(get("/health"), StatusCode::OK);
+        assert_eq!(get("/"), StatusCode::OK);
+    }
+
+    #[test]
+    fn metrics() {
+        assert_eq!(get("/metrics"), StatusCode::OK);
+    }
+
+    #[test]
+    fn nothing_else() {
+        assert_eq!(get("/index.html"), StatusCode::NOT_FOUND);
+    }
+}
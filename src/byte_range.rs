@@ -0,0 +1,194 @@
+use bytes::Bytes;
+use hyper::header::{
+    HeaderMap, HeaderValue, CONTENT_LENGTH, CONTENT_RANGE, ETAG, IF_RANGE, LAST_MODIFIED, RANGE,
+};
+use hyper::http::response;
+use hyper::StatusCode;
+use std::ops::Range;
+
+// What a Range header asks for out of a body of a given length
+#[derive(Debug, PartialEq)]
+pub enum Resolved {
+    // not something we serve a part for - several ranges, or nonsense - so
+    // the whole body goes out instead
+    Whole,
+    Part(Range<usize>),
+    Unsatisfiable,
+}
+
+pub fn resolve(header: &str, len: usize) -> Resolved {
+    let Some(spec) = header.trim().strip_prefix("bytes=") else {
+        return Resolved::Whole;
+    };
+    if spec.contains(',') {
+        return Resolved::Whole;
+    }
+    let Some((first, last)) = spec.trim().split_once('-') else {
+        return Resolved::Whole;
+    };
+    let (first, last) = (first.trim(), last.trim());
+
+    if first.is_empty() {
+        // the last N bytes
+        return match last.parse::<usize>() {
+            Ok(0) => Resolved::Unsatisfiable,
+            Ok(_) if len == 0 => Resolved::Unsatisfiable,
+            Ok(suffix) => Resolved::Part(len.saturating_sub(suffix)..len),
+            Err(_) => Resolved::Whole,
+        };
+    }
+    let Ok(first) = first.parse::<usize>() else {
+        return Resolved::Whole;
+    };
+    let end = if last.is_empty() {
+        len
+    } else {
+        match last.parse::<usize>() {
+            Ok(last) if last >= first => len.min(last.saturating_add(1)),
+            _ => return Resolved::Whole,
+        }
+    };
+    if first >= len {
+        return Resolved::Unsatisfiable;
+    }
+    Resolved::Part(first..end)
+}
+
+// An If-Range only lets the range through if it still names this body. Weak
+// ETags never match, so a rewritten body always goes out whole.
+fn if_range_matches(req_headers: &HeaderMap, resp_headers: &HeaderMap) -> bool {
+    let Some(if_range) = req_headers.get(IF_RANGE) else {
+        return true;
+    };
+    let Ok(if_range) = if_range.to_str() else {
+        return false;
+    };
+    if if_range.starts_with('"') {
+        return match resp_headers.get(ETAG) {
+            Some(etag) => etag.as_bytes() == if_range.as_bytes(),
+            None => false,
+        };
+    }
+    resp_headers
+        .get(LAST_MODIFIED)
+        .is_some_and(|last_modified| last_modified.as_bytes() == if_range.as_bytes())
+}
+
+// Cuts the part a client's Range asked for out of a complete response we've
+// rewritten, turning it into a 206 (or a 416 if there's no such part).
+// Anything but a 200 is left as it is.
+pub fn apply(req_headers: &HeaderMap, parts: &mut response::Parts, body: Bytes) -> Bytes {
+    let Some(range) = req_headers.get(RANGE) else {
+        return body;
+    };
+    let Ok(range) = range.to_str() else {
+        return body;
+    };
+    if parts.status != StatusCode::OK || !if_range_matches(req_headers, &parts.headers) {
+        return body;
+    }
+
+    match resolve(range, body.len()) {
+        Resolved::Whole => body,
+        Resolved::Part(part) => {
+            let content_range = format!("bytes {}-{}/{}", part.start, part.end - 1, body.len());
+            parts.status = StatusCode::PARTIAL_CONTENT;
+            parts.headers.insert(
+                CONTENT_RANGE,
+                HeaderValue::from_str(&content_range).unwrap(),
+            );
+            parts
+                .headers
+                .insert(CONTENT_LENGTH, HeaderValue::from(part.len()));
+            body.slice(part)
+        }
+        Resolved::Unsatisfiable => {
+            let content_range = format!("bytes */{}", body.len());
+            parts.status = StatusCode::RANGE_NOT_SATISFIABLE;
+            parts.headers.insert(
+                CONTENT_RANGE,
+                HeaderValue::from_str(&content_range).unwrap(),
+            );
+            parts.headers.insert(CONTENT_LENGTH, HeaderValue::from(0));
+            Bytes::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderName;
+    use hyper::Response;
+
+    #[test]
+    fn resolves_ranges() {
+        assert_eq!(resolve("bytes=0-4", 10), Resolved::Part(0..5));
+        assert_eq!(resolve("bytes=5-", 10), Resolved::Part(5..10));
+        assert_eq!(resolve("bytes=5-100", 10), Resolved::Part(5..10));
+        assert_eq!(resolve("bytes=-3", 10), Resolved::Part(7..10));
+        assert_eq!(resolve("bytes=-30", 10), Resolved::Part(0..10));
+        assert_eq!(resolve("bytes = 2-3", 10), Resolved::Whole);
+        assert_eq!(resolve("bytes=10-", 10), Resolved::Unsatisfiable);
+        assert_eq!(resolve("bytes=-0", 10), Resolved::Unsatisfiable);
+        assert_eq!(resolve("bytes=0-1,4-5", 10), Resolved::Whole);
+        assert_eq!(resolve("bytes=5-4", 10), Resolved::Whole);
+        assert_eq!(resolve("lines=1-2", 10), Resolved::Whole);
+    }
+
+    fn apply_to(req: &[(&str, &str)], resp: &[(&str, &str)]) -> (response::Parts, Bytes) {
+        let mut req_headers = HeaderMap::new();
+        for (name, value) in req {
+            req_headers.insert(
+                HeaderName::from_bytes(name.as_bytes()).unwrap(),
+                value.parse().unwrap(),
+            );
+        }
+        let mut builder = Response::builder();
+        for (name, value) in resp {
+            builder = builder.header(*name, *value);
+        }
+        let mut parts = builder.body(()).unwrap().into_parts().0;
+        let body = apply(&req_headers, &mut parts, Bytes::from("0123456789"));
+        (parts, body)
+    }
+
+    #[test]
+    fn slices_the_body() {
+        let (parts, body) = apply_to(&[("Range", "bytes=2-4")], &[]);
+        assert_eq!(parts.status, StatusCode::PARTIAL_CONTENT);
+        assert_eq!(parts.headers[CONTENT_RANGE], "bytes 2-4/10");
+        assert_eq!(parts.headers[CONTENT_LENGTH], "3");
+        assert_eq!(body, Bytes::from("234"));
+    }
+
+    #[test]
+    fn refuses_ranges_past_the_end() {
+        let (parts, body) = apply_to(&[("Range", "bytes=20-")], &[]);
+        assert_eq!(parts.status, StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(parts.headers[CONTENT_RANGE], "bytes */10");
+        assert!(body.is_empty());
+    }
+
+    #[test]
+    fn if_range_has_to_match() {
+        let last_modified = "Sun, 06 Nov 1994 08:49:37 GMT";
+        let (parts, _) = apply_to(
+            &[("Range", "bytes=2-4"), ("If-Range", "W/\"abc\"")],
+            &[("ETag", "W/\"abc\"")],
+        );
+        assert_eq!(parts.status, StatusCode::OK);
+        let (parts, _) = apply_to(
+            &[("Range", "bytes=2-4"), ("If-Range", last_modified)],
+            &[("Last-Modified", last_modified)],
+        );
+        assert_eq!(parts.status, StatusCode::PARTIAL_CONTENT);
+    }
+
+    #[test]
+    fn whole_body_without_a_range() {
+        let (parts, body) = apply_to(&[], &[]);
+        assert_eq!(parts.status, StatusCode::OK);
+        assert_eq!(body.len(), 10);
+    }
+}
@@ -0,0 +1,348 @@
+use crate::content_type;
+use crate::html::is_html;
+
+use bytes::Bytes;
+use encoding_rs::{EncoderResult, Encoding, UTF_8};
+use lol_html::{element, rewrite_str, RewriteStrSettings};
+use std::error::Error;
+use std::str::FromStr;
+
+// how far into an HTML document we look for a <meta charset> when the
+// Content-Type doesn't tell us - same as browsers' prescan
+const META_PRESCAN_LENGTH: usize = 1024;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Fallback {
+    // &#8217; - only understood in HTML
+    Entities,
+    // ' - close enough, and works everywhere
+    Ascii,
+}
+
+impl FromStr for Fallback {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "entities" => Ok(Fallback::Entities),
+            "ascii" => Ok(Fallback::Ascii),
+            other => Err(format!("unknown charset fallback {:?}", other)),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct TargetCharset {
+    // the name we put in Content-Type and <meta charset> - exactly as
+    // configured, since old browsers can be picky about what they recognise
+    pub label: String,
+    encoding: &'static Encoding,
+    // encoding_rs follows the WHATWG and treats ISO-8859-1 and US-ASCII as
+    // windows-1252. we really do want the stricter charsets, so those are
+    // encoded by hand: every char up to max_char maps straight to a byte.
+    max_char: Option<char>,
+    pub html_fallback: Fallback,
+}
+
+impl TargetCharset {
+    pub fn new(label: &str, html_fallback: Fallback) -> Result<TargetCharset, String> {
+        let label = label.trim();
+        let encoding = Encoding::for_label(label.as_bytes())
+            .ok_or_else(|| format!("unknown charset {:?}", label))?;
+        let max_char = match label.to_ascii_lowercase().as_str() {
+            "iso-8859-1" | "iso8859-1" | "iso_8859-1" | "latin1" | "l1" => Some('\u{ff}'),
+            "us-ascii" | "ascii" => Some('\u{7f}'),
+            _ => None,
+        };
+
+        Ok(TargetCharset {
+            label: label.to_string(),
+            encoding,
+            max_char,
+            html_fallback,
+        })
+    }
+
+    fn is_same_as(&self, encoding: &'static Encoding) -> bool {
+        self.max_char.is_none() && self.encoding == encoding
+    }
+}
+
+pub struct TranscodedText {
+    pub body: Bytes,
+    pub content_type: String,
+}
+
+// Re-encodes a text body into the target charset. Returns None if it's already
+// in that charset.
+pub fn transcode(
+    body: &[u8],
+    content_type: &str,
+    target: &TargetCharset,
+) -> Result<Option<TranscodedText>, Box<dyn Error>> {
+    let is_html = is_html(content_type);
+    let source = source_encoding(body, content_type, is_html);
+    if target.is_same_as(source) {
+        return Ok(None);
+    }
+
+    let (text, _, _) = source.decode(body);
+    let text = if is_html {
+        rewrite_meta_charset(&text, &target.label)?
+    } else if content_type::is(content_type, "text/css") {
+        rewrite_css_charset(&text, &target.label)
+    } else {
+        text.into_owned()
+    };
+
+    let fallback = if is_html {
+        target.html_fallback
+    } else {
+        Fallback::Ascii
+    };
+
+    Ok(Some(TranscodedText {
+        body: Bytes::from(encode(&text, target, fallback)),
+        content_type: content_type::with_charset(content_type, &target.label),
+    }))
+}
+
+// Content-Type charset first, then a <meta> in the document, then UTF-8. A BOM
+// overrides all of these when decoding anyway.
+fn source_encoding(body: &[u8], content_type: &str, is_html: bool) -> &'static Encoding {
+    content_type::charset(content_type)
+        .and_then(|label| Encoding::for_label(label.as_bytes()))
+        .or_else(|| {
+            if is_html {
+                prescan_meta_charset(body)
+            } else {
+                None
+            }
+        })
+        .unwrap_or(UTF_8)
+}
+
+fn prescan_meta_charset(body: &[u8]) -> Option<&'static Encoding> {
+    let head = &body[..body.len().min(META_PRESCAN_LENGTH)];
+    let head = String::from_utf8_lossy(head).to_ascii_lowercase();
+    let start = head.find("charset=")? + "charset=".len();
+    let label: String = head[start..]
+        .trim_start_matches(['"', '\''])
+        .chars()
+        .take_while(|c| !matches!(c, '"' | '\'' | ';' | '>' | '/') && !c.is_whitespace())
+        .collect();
+    Encoding::for_label(label.as_bytes())
+}
+
+fn rewrite_meta_charset(html: &str, label: &str) -> Result<String, Box<dyn Error>> {
+    let output = rewrite_str(
+        html,
+        RewriteStrSettings {
+            element_content_handlers: vec![
+                element!("meta[charset]", |el| {
+                    el.set_attribute("charset", label)?;
+                    Ok(())
+                }),
+                element!("meta[http-equiv][content]", |el| {
+                    let http_equiv = el.get_attribute("http-equiv").unwrap_or_default();
+                    if http_equiv.eq_ignore_ascii_case("content-type") {
+                        let content = el.get_attribute("content").unwrap_or_default();
+                        el.set_attribute("content", &content_type::with_charset(&content, label))?;
+                    }
+                    Ok(())
+                }),
+            ],
+            ..RewriteStrSettings::new()
+        },
+    )?;
+    Ok(output)
+}
+
+fn rewrite_css_charset(css: &str, label: &str) -> String {
+    const AT_CHARSET: &str = "@charset \"";
+    match css
+        .strip_prefix(AT_CHARSET)
+        .and_then(|rest| rest.split_once('"'))
+    {
+        Some((_, rest)) => format!("{}{}\"{}", AT_CHARSET, label, rest),
+        None => css.to_string(),
+    }
+}
+
+fn encode(text: &str, target: &TargetCharset, fallback: Fallback) -> Vec<u8> {
+    let mut output = Vec::with_capacity(text.len());
+
+    if let Some(max_char) = target.max_char {
+        for chr in text.chars() {
+            if chr <= max_char {
+                output.push(chr as u8);
+            } else {
+                push_fallback(&mut output, chr, fallback);
+            }
+        }
+        return output;
+    }
+
+    let mut encoder = target.encoding.new_encoder();
+    let mut remaining = text;
+    loop {
+        if let Some(needed) =
+            encoder.max_buffer_length_from_utf8_without_replacement(remaining.len())
+        {
+            output.reserve(needed);
+        }
+        let (result, read) =
+            encoder.encode_from_utf8_to_vec_without_replacement(remaining, &mut output, true);
+        remaining = &remaining[read..];
+        match result {
+            EncoderResult::InputEmpty => return output,
+            EncoderResult::OutputFull => {}
+            EncoderResult::Unmappable(chr) => push_fallback(&mut output, chr, fallback),
+        }
+    }
+}
+
+fn push_fallback(output: &mut Vec<u8>, chr: char, fallback: Fallback) {
+    match fallback {
+        Fallback::Entities => output.extend(format!("&#{};", chr as u32).bytes()),
+        Fallback::Ascii => output.extend(ascii_fallback(chr).bytes()),
+    }
+}
+
+fn ascii_fallback(chr: char) -> &'static str {
+    match chr {
+        '\u{2018}' | '\u{2019}' | '\u{201a}' | '\u{2032}' => "'",
+        '\u{201c}' | '\u{201d}' | '\u{201e}' | '\u{2033}' | '\u{ab}' | '\u{bb}' => "\"",
+        '\u{2010}'..='\u{2015}' | '\u{2212}' => "-",
+        '\u{2026}' => "...",
+        '\u{2022}' | '\u{b7}' => "*",
+        '\u{a0}' | '\u{2002}'..='\u{200a}' => " ",
+        '\u{200b}'..='\u{200d}' | '\u{feff}' => "",
+        '\u{a9}' => "(c)",
+        '\u{ae}' => "(R)",
+        '\u{2122}' => "(TM)",
+        '\u{20ac}' => "EUR",
+        '\u{a3}' => "GBP",
+        _ => "?",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(label: &str) -> TargetCharset {
+        TargetCharset::new(label, Fallback::Entities).unwrap()
+    }
+
+    fn transcode_str(body: &str, content_type: &str, label: &str) -> (Vec<u8>, String) {
+        let result = transcode(body.as_bytes(), content_type, &target(label))
+            .unwrap()
+            .unwrap();
+        (result.body.to_vec(), result.content_type)
+    }
+
+    #[test]
+    fn unknown_charset_is_an_error() {
+        assert!(TargetCharset::new("klingon", Fallback::Ascii).is_err());
+    }
+
+    #[test]
+    fn utf8_to_windows_1252() {
+        let (body, content_type) = transcode_str(
+            "caf\u{e9} \u{2019}",
+            "text/plain; charset=utf-8",
+            "windows-1252",
+        );
+        assert_eq!(body, b"caf\xe9 \x92");
+        assert_eq!(content_type, "text/plain; charset=windows-1252");
+    }
+
+    #[test]
+    fn latin1_does_not_get_windows_1252_extras() {
+        let (body, _) = transcode_str("\u{2019}", "text/plain", "iso-8859-1");
+        assert_eq!(body, b"'");
+    }
+
+    #[test]
+    fn html_unmappables_become_entities() {
+        let (body, _) = transcode_str("<p>\u{2603}</p>", "text/html", "iso-8859-1");
+        assert_eq!(body, b"<p>&#9731;</p>");
+    }
+
+    #[test]
+    fn html_ascii_fallback_when_configured() {
+        let target = TargetCharset::new("iso-8859-1", Fallback::Ascii).unwrap();
+        let result = transcode("<p>\u{2026}</p>".as_bytes(), "text/html", &target)
+            .unwrap()
+            .unwrap();
+        assert_eq!(&result.body[..], b"<p>...</p>");
+    }
+
+    #[test]
+    fn css_unmappables_never_become_entities() {
+        let (body, _) = transcode_str("a::after { content: \"\u{2603}\" }", "text/css", "latin1");
+        assert_eq!(body, b"a::after { content: \"?\" }");
+    }
+
+    #[test]
+    fn shift_jis() {
+        let (body, _) = transcode_str("\u{65e5}\u{672c}", "text/plain; charset=utf-8", "Shift_JIS");
+        assert_eq!(body, b"\x93\xfa\x96\x7b");
+    }
+
+    #[test]
+    fn already_in_target_charset_is_left_alone() {
+        let result = transcode(
+            b"hello",
+            "text/html; charset=windows-1252",
+            &target("windows-1252"),
+        )
+        .unwrap();
+        assert!(result.is_none());
+    }
+
+    #[test]
+    fn meta_charset_is_rewritten() {
+        let (body, _) = transcode_str(
+            "<html><head><meta charset=\"utf-8\"></head></html>",
+            "text/html",
+            "windows-1252",
+        );
+        assert_eq!(
+            body,
+            b"<html><head><meta charset=\"windows-1252\"></head></html>"
+        );
+    }
+
+    #[test]
+    fn meta_http_equiv_is_rewritten() {
+        let (body, _) = transcode_str(
+            "<meta http-equiv=\"Content-Type\" content=\"text/html; charset=utf-8\">",
+            "text/html",
+            "windows-1252",
+        );
+        assert_eq!(
+            body,
+            b"<meta http-equiv=\"Content-Type\" content=\"text/html; charset=windows-1252\">"
+        );
+    }
+
+    #[test]
+    fn css_at_charset_is_rewritten() {
+        let (body, _) = transcode_str("@charset \"utf-8\";\nb {}", "text/css", "windows-1252");
+        assert_eq!(body, b"@charset \"windows-1252\";\nb {}");
+    }
+
+    #[test]
+    fn source_charset_is_sniffed_from_meta() {
+        let body = b"<meta charset=iso-8859-2><p>\xb1</p>";
+        let result = transcode(body, "text/html", &target("utf-8"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(result.body.to_vec()).unwrap(),
+            "<meta charset=\"utf-8\"><p>\u{105}</p>"
+        );
+    }
+}
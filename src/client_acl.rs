@@ -0,0 +1,122 @@
+use crate::client_profile::parse_network;
+use crate::config::{env_list, env_parse};
+
+use ipnet::IpNet;
+use std::error::Error;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+// What happens to clients the ACL turns away
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum Rejection {
+    // hang up as soon as we know who they are
+    #[default]
+    Close,
+    // answer each request with a 403 page, so people can see why
+    Page,
+}
+
+impl FromStr for Rejection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "close" => Ok(Rejection::Close),
+            "page" => Ok(Rejection::Page),
+            other => Err(format!("unknown rejection {:?}", other)),
+        }
+    }
+}
+
+// Which client addresses may use a listener. Denials win over allows, and an
+// empty allow list allows everyone not denied.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ClientAcl {
+    allowed: Vec<IpNet>,
+    denied: Vec<IpNet>,
+    pub rejection: Rejection,
+}
+
+impl ClientAcl {
+    pub fn new(allowed: Vec<IpNet>, denied: Vec<IpNet>, rejection: Rejection) -> ClientAcl {
+        ClientAcl {
+            allowed,
+            denied,
+            rejection,
+        }
+    }
+
+    // reads {prefix}ALLOWED_CLIENTS, {prefix}DENIED_CLIENTS and
+    // {prefix}REJECT_CLIENTS_WITH
+    pub fn from_env(prefix: &str) -> Result<ClientAcl, Box<dyn Error>> {
+        Ok(ClientAcl::new(
+            networks_from_env(&format!("{}ALLOWED_CLIENTS", prefix))?,
+            networks_from_env(&format!("{}DENIED_CLIENTS", prefix))?,
+            env_parse(&format!("{}REJECT_CLIENTS_WITH", prefix))?.unwrap_or_default(),
+        ))
+    }
+
+    pub fn allows(&self, client: IpAddr) -> bool {
+        let client = client.to_canonical();
+        let allowed =
+            self.allowed.is_empty() || self.allowed.iter().any(|net| net.contains(&client));
+        allowed && !self.denied.iter().any(|net| net.contains(&client))
+    }
+}
+
+pub fn networks_from_env(name: &str) -> Result<Vec<IpNet>, Box<dyn Error>> {
+    env_list(name)?
+        .iter()
+        .map(|network| parse_network(network))
+        .collect::<Result<Vec<IpNet>, String>>()
+        .map_err(|err| format!("{} was not valid: {}", name, err).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn acl(allowed: &[&str], denied: &[&str]) -> ClientAcl {
+        ClientAcl::new(
+            allowed.iter().map(|net| net.parse().unwrap()).collect(),
+            denied.iter().map(|net| net.parse().unwrap()).collect(),
+            Rejection::Close,
+        )
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn everyone_is_allowed_by_default() {
+        assert!(ClientAcl::default().allows(ip("203.0.113.9")));
+    }
+
+    #[test]
+    fn allow_list_limits_clients() {
+        let acl = acl(&["192.168.200.0/24"], &[]);
+        assert!(acl.allows(ip("192.168.200.7")));
+        assert!(!acl.allows(ip("203.0.113.9")));
+    }
+
+    #[test]
+    fn deny_wins_over_allow() {
+        let acl = acl(&["192.168.200.0/24"], &["192.168.200.66/32"]);
+        assert!(acl.allows(ip("192.168.200.7")));
+        assert!(!acl.allows(ip("192.168.200.66")));
+    }
+
+    #[test]
+    fn ipv4_mapped_clients_match_ipv4_networks() {
+        let acl = acl(&["192.168.200.0/24"], &[]);
+        assert!(acl.allows(ip("::ffff:192.168.200.7")));
+    }
+
+    #[test]
+    fn parse_rejections() {
+        assert_eq!("page".parse(), Ok(Rejection::Page));
+        assert_eq!("Close".parse(), Ok(Rejection::Close));
+        assert!("ignore".parse::<Rejection>().is_err());
+    }
+}
@@ -0,0 +1,302 @@
+use crate::charset_transcoder::{Fallback, TargetCharset};
+use crate::config::{env_flag, env_list, env_name, env_parse, env_string};
+use crate::html_simplifier::{HtmlSimplification, DEFAULT_MAX_INLINE_STYLE_LENGTH};
+use crate::image_transcoder::{ImageTranscoding, TargetFormat, DEFAULT_JPEG_QUALITY};
+
+use ipnet::IpNet;
+use regex::Regex;
+use std::error::Error;
+use std::net::IpAddr;
+
+pub const DEFAULT_PROFILE_NAME: &str = "default";
+
+// What the browsers on the other end of the proxy can cope with, and so which
+// transformations we apply to responses on their behalf. By default, none.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ClientProfile {
+    pub image_transcoding: Option<ImageTranscoding>,
+    pub charset: Option<TargetCharset>,
+    pub html_simplification: Option<HtmlSimplification>,
+    // how wide an image we pick out of a srcset, if we collapse them at all
+    pub srcset_target_width: Option<u32>,
+}
+
+impl ClientProfile {
+    // reads settings from variables like {prefix}TARGET_CHARSET
+    pub fn from_env(prefix: &str) -> Result<ClientProfile, Box<dyn Error>> {
+        Ok(ClientProfile {
+            image_transcoding: image_transcoding_from_env(prefix)?,
+            charset: charset_from_env(prefix)?,
+            html_simplification: html_simplification_from_env(prefix)?,
+            srcset_target_width: env_parse(&format!("{}SRCSET_TARGET_WIDTH", prefix))?,
+        })
+    }
+}
+
+// A profile for some particular machines on the network, picked out by their
+// User-Agent or IP address.
+#[derive(Debug, Clone)]
+pub struct NamedProfile {
+    pub name: String,
+    user_agent: Option<Regex>,
+    clients: Vec<IpNet>,
+    pub profile: ClientProfile,
+}
+
+impl NamedProfile {
+    pub fn new(
+        name: &str,
+        user_agent: Option<Regex>,
+        clients: Vec<IpNet>,
+        profile: ClientProfile,
+    ) -> NamedProfile {
+        NamedProfile {
+            name: name.to_string(),
+            user_agent,
+            clients,
+            profile,
+        }
+    }
+
+    // Named profiles are configured with the same variables as the default
+    // profile, prefixed with PROFILE_{NAME}_ - e.g. PROFILE_MAC_TARGET_CHARSET.
+    // Anything not set gets the usual default rather than the default
+    // profile's setting.
+    fn from_env(name: &str) -> Result<NamedProfile, Box<dyn Error>> {
+        let prefix = format!("PROFILE_{}_", env_name(name));
+
+        let user_agent_var = format!("{}USER_AGENT", prefix);
+        let user_agent = match env_string(&user_agent_var)? {
+            Some(pattern) if !pattern.is_empty() => Some(
+                Regex::new(&pattern)
+                    .map_err(|err| format!("{} was not valid: {}", user_agent_var, err))?,
+            ),
+            _ => None,
+        };
+
+        let clients_var = format!("{}CLIENTS", prefix);
+        let clients = env_list(&clients_var)?
+            .iter()
+            .map(|client| parse_network(client))
+            .collect::<Result<Vec<IpNet>, String>>()
+            .map_err(|err| format!("{} was not valid: {}", clients_var, err))?;
+
+        if user_agent.is_none() && clients.is_empty() {
+            return Err(format!(
+                "profile {} needs {} or {} to say which clients it's for",
+                name, user_agent_var, clients_var
+            )
+            .into());
+        }
+
+        Ok(NamedProfile::new(
+            name,
+            user_agent,
+            clients,
+            ClientProfile::from_env(&prefix)?,
+        ))
+    }
+
+    fn matches(&self, user_agent: Option<&str>, client: IpAddr) -> bool {
+        let client = client.to_canonical();
+        let user_agent_matches = match (&self.user_agent, user_agent) {
+            (Some(regex), Some(user_agent)) => regex.is_match(user_agent),
+            _ => false,
+        };
+        user_agent_matches || self.clients.iter().any(|net| net.contains(&client))
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ClientProfiles {
+    default: ClientProfile,
+    named: Vec<NamedProfile>,
+}
+
+impl ClientProfiles {
+    pub fn new(default: ClientProfile, named: Vec<NamedProfile>) -> ClientProfiles {
+        ClientProfiles { default, named }
+    }
+
+    // PROFILES lists the named profiles, in the order they're tried
+    pub fn from_env() -> Result<ClientProfiles, Box<dyn Error>> {
+        let named = env_list("PROFILES")?
+            .iter()
+            .map(|name| NamedProfile::from_env(name))
+            .collect::<Result<Vec<NamedProfile>, Box<dyn Error>>>()?;
+
+        Ok(ClientProfiles::new(ClientProfile::from_env("")?, named))
+    }
+
+    // the profile with this name, if there is one
+    pub fn get(&self, name: &str) -> Option<&ClientProfile> {
+        if name == DEFAULT_PROFILE_NAME {
+            return Some(&self.default);
+        }
+        self.named
+            .iter()
+            .find(|named| named.name == name)
+            .map(|named| &named.profile)
+    }
+
+    // returns the first named profile matching the client, or the default
+    pub fn select(&self, user_agent: Option<&str>, client: IpAddr) -> (&str, &ClientProfile) {
+        self.named
+            .iter()
+            .find(|named| named.matches(user_agent, client))
+            .map(|named| (named.name.as_str(), &named.profile))
+            .unwrap_or((DEFAULT_PROFILE_NAME, &self.default))
+    }
+}
+
+// accepts CIDR ranges and plain addresses
+pub fn parse_network(network: &str) -> Result<IpNet, String> {
+    network
+        .parse::<IpNet>()
+        .or_else(|_| network.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("{:?} is not an IP address or network", network))
+}
+
+fn image_transcoding_from_env(prefix: &str) -> Result<Option<ImageTranscoding>, Box<dyn Error>> {
+    let target_var = format!("{}TRANSCODE_IMAGES_TO", prefix);
+    let target = match env_string(&target_var)? {
+        Some(target) if !target.trim().is_empty() => target
+            .parse::<TargetFormat>()
+            .map_err(|err| format!("{} was not valid: {}", target_var, err))?,
+        _ => return Ok(None),
+    };
+
+    Ok(Some(ImageTranscoding {
+        target,
+        max_dimension: env_parse(&format!("{}MAX_IMAGE_DIMENSION", prefix))?,
+        jpeg_quality: env_parse(&format!("{}JPEG_QUALITY", prefix))?
+            .unwrap_or(DEFAULT_JPEG_QUALITY),
+    }))
+}
+
+fn charset_from_env(prefix: &str) -> Result<Option<TargetCharset>, Box<dyn Error>> {
+    let charset_var = format!("{}TARGET_CHARSET", prefix);
+    let label = match env_string(&charset_var)? {
+        Some(label) if !label.trim().is_empty() => label,
+        _ => return Ok(None),
+    };
+    let fallback_var = format!("{}CHARSET_FALLBACK", prefix);
+    let fallback = match env_string(&fallback_var)? {
+        Some(fallback) => fallback
+            .parse()
+            .map_err(|err| format!("{} was not valid: {}", fallback_var, err))?,
+        None => Fallback::Entities,
+    };
+
+    TargetCharset::new(&label, fallback)
+        .map(Some)
+        .map_err(|err| format!("{} was not valid: {}", charset_var, err).into())
+}
+
+fn html_simplification_from_env(
+    prefix: &str,
+) -> Result<Option<HtmlSimplification>, Box<dyn Error>> {
+    if !env_flag(&format!("{}SIMPLIFY_HTML", prefix))? {
+        return Ok(None);
+    }
+
+    Ok(Some(HtmlSimplification {
+        max_inline_style_length: env_parse(&format!("{}MAX_INLINE_STYLE_LENGTH", prefix))?
+            .unwrap_or(DEFAULT_MAX_INLINE_STYLE_LENGTH),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn small_images_profile() -> ClientProfile {
+        ClientProfile {
+            srcset_target_width: Some(320),
+            ..ClientProfile::default()
+        }
+    }
+
+    fn profiles() -> ClientProfiles {
+        ClientProfiles::new(
+            ClientProfile::default(),
+            vec![
+                NamedProfile::new(
+                    "mac",
+                    Some(Regex::new("Macintosh; .*PPC").unwrap()),
+                    vec![],
+                    small_images_profile(),
+                ),
+                NamedProfile::new(
+                    "lab",
+                    None,
+                    vec!["192.168.200.0/24".parse().unwrap()],
+                    ClientProfile::default(),
+                ),
+            ],
+        )
+    }
+
+    #[test]
+    fn select_by_user_agent() {
+        let profiles = profiles();
+        let (name, profile) = profiles.select(
+            Some("Mozilla/4.0 (compatible; MSIE 5.0; Macintosh; I; PPC)"),
+            "10.0.0.1".parse().unwrap(),
+        );
+        assert_eq!(name, "mac");
+        assert_eq!(profile, &small_images_profile());
+    }
+
+    #[test]
+    fn select_by_client_ip() {
+        let profiles = profiles();
+        let (name, _) = profiles.select(None, "192.168.200.7".parse().unwrap());
+        assert_eq!(name, "lab");
+    }
+
+    #[test]
+    fn select_by_ipv4_mapped_client_ip() {
+        let profiles = profiles();
+        let (name, _) = profiles.select(None, "::ffff:192.168.200.7".parse().unwrap());
+        assert_eq!(name, "lab");
+    }
+
+    #[test]
+    fn first_matching_profile_wins() {
+        let profiles = profiles();
+        let (name, _) = profiles.select(
+            Some("Mozilla/4.0 (compatible; MSIE 5.0; Macintosh; I; PPC)"),
+            "192.168.200.7".parse().unwrap(),
+        );
+        assert_eq!(name, "mac");
+    }
+
+    #[test]
+    fn select_falls_back_to_default() {
+        let profiles = profiles();
+        let (name, profile) = profiles.select(Some("Lynx/2.8"), "10.0.0.1".parse().unwrap());
+        assert_eq!(name, DEFAULT_PROFILE_NAME);
+        assert_eq!(profile, &ClientProfile::default());
+    }
+
+    #[test]
+    fn get_by_name() {
+        let profiles = profiles();
+        assert_eq!(profiles.get("mac"), Some(&small_images_profile()));
+        assert_eq!(
+            profiles.get(DEFAULT_PROFILE_NAME),
+            Some(&ClientProfile::default())
+        );
+        assert_eq!(profiles.get("amiga"), None);
+    }
+
+    #[test]
+    fn parse_network_accepts_plain_addresses() {
+        assert_eq!(
+            parse_network("10.1.2.3"),
+            Ok("10.1.2.3/32".parse().unwrap())
+        );
+        assert!(parse_network("10.1.2.3/33").is_err());
+    }
+}
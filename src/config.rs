@@ -0,0 +1,107 @@
+use std::env;
+use std::error::Error;
+use std::str::FromStr;
+
+// Helpers for reading settings out of environment variables. Every setting is
+// optional - a missing variable gives None (or false) so callers can fall back
+// to their defaults.
+
+pub fn env_string(name: &str) -> Result<Option<String>, Box<dyn Error>> {
+    match env::var(name) {
+        Ok(value) => Ok(Some(value)),
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(env::VarError::NotUnicode(_)) => Err(format!("{} was not valid", name).into()),
+    }
+}
+
+pub fn env_parse<T: FromStr>(name: &str) -> Result<Option<T>, Box<dyn Error>> {
+    match env_string(name)? {
+        Some(value) => match value.trim().parse() {
+            Ok(parsed) => Ok(Some(parsed)),
+            Err(_) => Err(format!("{} was not valid: {:?}", name, value).into()),
+        },
+        None => Ok(None),
+    }
+}
+
+pub fn env_flag(name: &str) -> Result<bool, Box<dyn Error>> {
+    match env_string(name)? {
+        Some(value) => {
+            parse_flag(&value).ok_or_else(|| format!("{} should be true or false", name).into())
+        }
+        None => Ok(false),
+    }
+}
+
+// comma-separated list, with whitespace around each item trimmed and empty
+// items dropped
+pub fn env_list(name: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    Ok(env_string(name)?
+        .map(|value| parse_list(&value))
+        .unwrap_or_default())
+}
+
+// turns a name like "mac-classic" into "MAC_CLASSIC", for building the
+// variable names of named profiles, listeners and so on
+pub fn env_name(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn parse_flag(value: &str) -> Option<bool> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "1" | "true" | "yes" | "on" => Some(true),
+        "0" | "false" | "no" | "off" | "" => Some(false),
+        _ => None,
+    }
+}
+
+fn parse_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_flag_accepts_common_spellings() {
+        assert_eq!(parse_flag("true"), Some(true));
+        assert_eq!(parse_flag(" YES "), Some(true));
+        assert_eq!(parse_flag("1"), Some(true));
+        assert_eq!(parse_flag("off"), Some(false));
+        assert_eq!(parse_flag(""), Some(false));
+    }
+
+    #[test]
+    fn parse_flag_rejects_nonsense() {
+        assert_eq!(parse_flag("maybe"), None);
+    }
+
+    #[test]
+    fn parse_list_trims_and_drops_empty_items() {
+        assert_eq!(parse_list(" a, b ,,c "), vec!["a", "b", "c"]);
+    }
+
+    #[test]
+    fn parse_list_of_empty_string_is_empty() {
+        assert!(parse_list("").is_empty());
+    }
+
+    #[test]
+    fn env_name_uppercases_and_replaces_punctuation() {
+        assert_eq!(env_name("mac-classic"), "MAC_CLASSIC");
+    }
+}
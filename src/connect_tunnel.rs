@@ -0,0 +1,310 @@
+use crate::config::{env_list, env_string};
+use crate::destination_policy::DestinationPolicy;
+use crate::dns_resolver::DnsResolver;
+use crate::error_page;
+use crate::host_pattern::{self, HostPattern};
+use crate::upstream_proxy::UpstreamProxy;
+
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::body::Incoming;
+use hyper::{Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use std::error::Error;
+use std::net::SocketAddr;
+use tokio::net::TcpStream;
+
+pub const DEFAULT_CONNECT_PORTS: &[u16] = &[443];
+
+// Which hosts clients using us as an explicit proxy may open CONNECT tunnels
+// to. With no allowed hosts (the default) CONNECT is refused outright.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ConnectPolicy {
+    allowed_hosts: Vec<HostPattern>,
+    allowed_ports: Vec<u16>,
+}
+
+impl Default for ConnectPolicy {
+    fn default() -> Self {
+        ConnectPolicy::new(vec![], Vec::from(DEFAULT_CONNECT_PORTS))
+    }
+}
+
+impl ConnectPolicy {
+    pub fn new(allowed_hosts: Vec<HostPattern>, allowed_ports: Vec<u16>) -> ConnectPolicy {
+        ConnectPolicy {
+            allowed_hosts,
+            allowed_ports,
+        }
+    }
+
+    pub fn from_env() -> Result<ConnectPolicy, Box<dyn Error>> {
+        let allowed_hosts = env_list("CONNECT_ALLOWED_HOSTS")?
+            .iter()
+            .map(|host| host.parse())
+            .collect::<Result<Vec<HostPattern>, String>>()
+            .map_err(|err| format!("CONNECT_ALLOWED_HOSTS was not valid: {}", err))?;
+
+        let allowed_ports = match env_string("CONNECT_ALLOWED_PORTS")? {
+            Some(_) => env_list("CONNECT_ALLOWED_PORTS")?
+                .iter()
+                .map(|port| port.parse())
+                .collect::<Result<Vec<u16>, _>>()
+                .map_err(|_| "CONNECT_ALLOWED_PORTS was not a list of port numbers")?,
+            None => Vec::from(DEFAULT_CONNECT_PORTS),
+        };
+
+        Ok(ConnectPolicy::new(allowed_hosts, allowed_ports))
+    }
+
+    pub fn is_allowed(&self, host: &str, port: u16) -> bool {
+        self.allowed_ports.contains(&port) && host_pattern::any_match(&self.allowed_hosts, host)
+    }
+}
+
+// Where a tunnel to host may go: the addresses it resolves to, minus any the
+// destination policy blocks. Addresses from DNS overrides are trusted, as
+// they are for ordinary requests.
+async fn allowed_addrs(
+    destination_policy: &DestinationPolicy,
+    resolver: &DnsResolver,
+    host: &str,
+    port: u16,
+) -> std::io::Result<Vec<SocketAddr>> {
+    let resolved = resolver.resolve(host).await?;
+    Ok(resolved
+        .addrs
+        .into_iter()
+        .filter(|ip| resolved.overridden || destination_policy.allows_address(*ip))
+        .map(|ip| SocketAddr::new(ip, port))
+        .collect())
+}
+
+// Handles a CONNECT request: once the origin connection is open, answers 200
+// and then shovels bytes both ways between the client and the origin until
+// either end hangs up. We never see inside the tunnel - it's TLS.
+pub async fn handle(
+    policy: &ConnectPolicy,
+    destination_policy: &DestinationPolicy,
+    resolver: &DnsResolver,
+    upstream_proxy: Option<&UpstreamProxy>,
+    req: Request<Incoming>,
+) -> Response<Full<Bytes>> {
+    let (host, port) = match req.uri().authority() {
+        Some(authority) => match authority.port_u16() {
+            Some(port) => (authority.host().to_string(), port),
+            None => return error_page::response(StatusCode::BAD_REQUEST, "CONNECT needs a port"),
+        },
+        None => return error_page::response(StatusCode::BAD_REQUEST, "CONNECT needs host:port"),
+    };
+
+    if !policy.is_allowed(&host, port) {
+        println!("= CONNECT to {}:{} is not allowed", host, port);
+        return error_page::response(
+            StatusCode::FORBIDDEN,
+            "Tunnelling to that host is not allowed",
+        );
+    }
+
+    let refused = || {
+        error_page::response(
+            StatusCode::FORBIDDEN,
+            "Tunnelling to that address is not allowed",
+        )
+    };
+    if let Err(err) = destination_policy.check_name(&host) {
+        println!("= Refusing CONNECT: {}", err);
+        return refused();
+    }
+    let connecting = match upstream_proxy {
+        // an upstream proxy resolves names itself
+        Some(upstream) if upstream.applies_to(&host) => upstream.connect(&host, port).await,
+        // connecting to the addresses we checked, not the name, so it can't
+        // resolve somewhere else in between
+        _ => match allowed_addrs(destination_policy, resolver, &host, port).await {
+            Ok(addrs) if addrs.is_empty() => {
+                println!("= Refusing CONNECT: {} is a private address", host);
+                return refused();
+            }
+            Ok(addrs) => TcpStream::connect(&addrs[..]).await,
+            Err(err) => Err(err),
+        },
+    };
+    let origin = match connecting {
+        Ok(origin) => origin,
+        Err(err) => {
+            println!("= CONNECT to {}:{} failed: {}", host, port, err);
+            return error_page::response(StatusCode::BAD_GATEWAY, "Couldn't connect to that host");
+        }
+    };
+
+    println!("= Opened tunnel to {}:{}", host, port);
+    tokio::task::spawn(async move {
+        match hyper::upgrade::on(req).await {
+            Ok(upgraded) => {
+                let mut client = TokioIo::new(upgraded);
+                let mut origin = origin;
+                match tokio::io::copy_bidirectional(&mut client, &mut origin).await {
+                    Ok((sent, received)) => println!(
+                        "= Closed tunnel to {}:{} ({} bytes sent, {} received)",
+                        host, port, sent, received
+                    ),
+                    Err(err) => println!("= Tunnel to {}:{} failed: {}", host, port, err),
+                }
+            }
+            Err(err) => println!("= CONNECT upgrade failed: {}", err),
+        }
+    });
+
+    Response::new(Full::new(Bytes::new()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::server::conn::http1;
+    use hyper::service::service_fn;
+    use std::convert::Infallible;
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    fn policy(hosts: &[&str]) -> ConnectPolicy {
+        ConnectPolicy::new(
+            hosts.iter().map(|host| host.parse().unwrap()).collect(),
+            vec![443],
+        )
+    }
+
+    #[test]
+    fn nothing_is_allowed_by_default() {
+        assert!(!ConnectPolicy::default().is_allowed("example.com", 443));
+    }
+
+    #[test]
+    fn allowed_host_and_port() {
+        let policy = policy(&["*.example.com"]);
+        assert!(policy.is_allowed("www.example.com", 443));
+        assert!(!policy.is_allowed("www.example.com", 22));
+        assert!(!policy.is_allowed("example.org", 443));
+    }
+
+    // a proxy that serves nothing but CONNECT, on a random local port
+    async fn start_proxy(
+        policy: ConnectPolicy,
+        destination_policy: DestinationPolicy,
+    ) -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let policies = Arc::new((policy, destination_policy));
+        tokio::task::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let service = service_fn(move |req| {
+                let policies = policies.clone();
+                async move {
+                    let (policy, destination_policy) = &*policies;
+                    let resolver = DnsResolver::default();
+                    let resp = handle(policy, destination_policy, &resolver, None, req).await;
+                    Ok::<_, Infallible>(resp)
+                }
+            });
+            http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .with_upgrades()
+                .await
+                .unwrap();
+        });
+        addr
+    }
+
+    async fn start_echo_server() -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::task::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 64];
+            let len = stream.read(&mut buf).await.unwrap();
+            stream.write_all(&buf[..len]).await.unwrap();
+        });
+        port
+    }
+
+    async fn read_response_head(stream: &mut TcpStream) -> String {
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            let mut byte = [0u8; 1];
+            if stream.read(&mut byte).await.unwrap() == 0 {
+                break;
+            }
+            head.push(byte[0]);
+        }
+        String::from_utf8(head).unwrap()
+    }
+
+    #[tokio::test]
+    async fn tunnels_bytes_to_allowed_host() {
+        let echo_port = start_echo_server().await;
+        let proxy = start_proxy(
+            ConnectPolicy::new(vec!["127.0.0.1".parse().unwrap()], vec![echo_port]),
+            DestinationPolicy::new(vec![], vec![], vec![]),
+        )
+        .await;
+
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        let request = format!(
+            "CONNECT 127.0.0.1:{0} HTTP/1.1\r\nHost: 127.0.0.1:{0}\r\n\r\n",
+            echo_port
+        );
+        client.write_all(request.as_bytes()).await.unwrap();
+        assert!(read_response_head(&mut client)
+            .await
+            .starts_with("HTTP/1.1 200"));
+
+        client.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[tokio::test]
+    async fn refuses_hosts_not_on_allow_list() {
+        let proxy = start_proxy(policy(&["example.com"]), DestinationPolicy::default()).await;
+
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client
+            .write_all(b"CONNECT 127.0.0.1:443 HTTP/1.1\r\nHost: 127.0.0.1:443\r\n\r\n")
+            .await
+            .unwrap();
+        assert!(read_response_head(&mut client)
+            .await
+            .starts_with("HTTP/1.1 403"));
+    }
+
+    #[tokio::test]
+    async fn refuses_private_addresses_even_when_any_host_is_allowed() {
+        let proxy = start_proxy(policy(&["*"]), DestinationPolicy::default()).await;
+
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client
+            .write_all(b"CONNECT 127.0.0.1:443 HTTP/1.1\r\nHost: 127.0.0.1:443\r\n\r\n")
+            .await
+            .unwrap();
+        assert!(read_response_head(&mut client)
+            .await
+            .starts_with("HTTP/1.1 403"));
+    }
+
+    #[tokio::test]
+    async fn refuses_names_that_resolve_to_private_addresses() {
+        let proxy = start_proxy(policy(&["*"]), DestinationPolicy::default()).await;
+
+        let mut client = TcpStream::connect(proxy).await.unwrap();
+        client
+            .write_all(b"CONNECT localhost:443 HTTP/1.1\r\nHost: localhost:443\r\n\r\n")
+            .await
+            .unwrap();
+        assert!(read_response_head(&mut client)
+            .await
+            .starts_with("HTTP/1.1 403"));
+    }
+}
@@ -0,0 +1,53 @@
+use crate::listener::Listener;
+use crate::proxy_protocol;
+
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::AsyncRead;
+
+// What we know about the client connection a request arrived on
+#[derive(Debug, Clone)]
+pub struct Connection {
+    // the browser - which, behind a load balancer, isn't who we're talking to
+    pub client_addr: SocketAddr,
+    // where the client was headed before iptables sent it to us
+    pub original_dst: Option<SocketAddr>,
+    pub listener: Arc<Listener>,
+}
+
+impl Connection {
+    // Works out who's on the other end of a freshly accepted stream, reading
+    // the PROXY protocol header off it first if the listener expects one
+    // from this peer.
+    pub async fn accept<S>(
+        stream: &mut S,
+        peer_addr: SocketAddr,
+        original_dst: Option<SocketAddr>,
+        listener: Arc<Listener>,
+    ) -> Result<Connection, Box<dyn Error + Send + Sync>>
+    where
+        S: AsyncRead + Unpin,
+    {
+        let client_addr = if listener.reads_proxy_header(peer_addr.ip()) {
+            let header = proxy_protocol::read_header(stream);
+            match tokio::time::timeout(proxy_protocol::HEADER_TIMEOUT, header).await {
+                Ok(Ok(Some(client_addr))) => {
+                    println!("= {} is connecting via {}", client_addr, peer_addr);
+                    client_addr
+                }
+                Ok(Ok(None)) => peer_addr,
+                Ok(Err(err)) => return Err(err),
+                Err(_) => return Err("timed out waiting for PROXY protocol header".into()),
+            }
+        } else {
+            peer_addr
+        };
+
+        Ok(Connection {
+            client_addr,
+            original_dst,
+            listener,
+        })
+    }
+}
@@ -0,0 +1,92 @@
+// Small helpers for picking apart Content-Type header values like
+// "text/html; charset=utf-8"
+
+// returns the "type/subtype" part of a content type, without any parameters
+pub fn essence(content_type: &str) -> &str {
+    match content_type.find(';') {
+        Some(loc) => content_type[..loc].trim(),
+        None => content_type.trim(),
+    }
+}
+
+pub fn is(content_type: &str, mime: &str) -> bool {
+    essence(content_type).eq_ignore_ascii_case(mime)
+}
+
+// returns the value of the charset parameter, if there is one
+pub fn charset(content_type: &str) -> Option<&str> {
+    content_type.split(';').skip(1).find_map(|param| {
+        let (name, value) = param.split_once('=')?;
+        if name.trim().eq_ignore_ascii_case("charset") {
+            Some(value.trim().trim_matches('"'))
+        } else {
+            None
+        }
+    })
+}
+
+// returns the content type with its charset parameter replaced (or added)
+pub fn with_charset(content_type: &str, charset: &str) -> String {
+    let mut result = String::from(essence(content_type));
+    for param in content_type.split(';').skip(1) {
+        let name = param.split('=').next().unwrap_or("").trim();
+        if !name.is_empty() && !name.eq_ignore_ascii_case("charset") {
+            result.push_str("; ");
+            result.push_str(param.trim());
+        }
+    }
+    result.push_str("; charset=");
+    result.push_str(charset);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn essence_strips_parameters() {
+        assert_eq!(essence("text/html; charset=utf-8"), "text/html");
+    }
+
+    #[test]
+    fn essence_without_parameters() {
+        assert_eq!(essence(" image/webp "), "image/webp");
+    }
+
+    #[test]
+    fn is_ignores_case_and_parameters() {
+        assert!(is("Image/WebP;q=1", "image/webp"));
+        assert!(!is("image/png", "image/webp"));
+    }
+
+    #[test]
+    fn charset_finds_parameter() {
+        assert_eq!(charset("text/html; Charset=\"UTF-8\""), Some("UTF-8"));
+        assert_eq!(
+            charset("text/html;foo=bar;charset=iso-8859-1"),
+            Some("iso-8859-1")
+        );
+    }
+
+    #[test]
+    fn charset_missing() {
+        assert_eq!(charset("text/html"), None);
+    }
+
+    #[test]
+    fn with_charset_replaces_existing_charset() {
+        assert_eq!(
+            with_charset("text/html; charset=utf-8", "windows-1252"),
+            "text/html; charset=windows-1252"
+        );
+    }
+
+    #[test]
+    fn with_charset_keeps_other_parameters() {
+        assert_eq!(
+            with_charset("text/plain; format=flowed", "Shift_JIS"),
+            "text/plain; format=flowed; charset=Shift_JIS"
+        );
+    }
+}
@@ -0,0 +1,347 @@
+use crate::config::{env_flag, env_list};
+use crate::dns_resolver::DnsResolver;
+use crate::host_pattern::{self, HostPattern};
+
+use hyper_util::client::legacy::connect::dns::Name;
+use ipnet::IpNet;
+use std::error::Error;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tower_service::Service;
+
+// Addresses nobody on the internet should be able to make us fetch from:
+// this machine, the network it's on, cloud metadata services and so on
+pub const PRIVATE_NETWORKS: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "100.64.0.0/10",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.0.0.0/24",
+    "192.168.0.0/16",
+    "198.18.0.0/15",
+    "224.0.0.0/4",
+    "240.0.0.0/4",
+    "::/128",
+    "::1/128",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+// Which origins the proxy will fetch from
+#[derive(Debug, PartialEq, Clone)]
+pub struct DestinationPolicy {
+    // empty means any host
+    allowed_hosts: Vec<HostPattern>,
+    denied_hosts: Vec<HostPattern>,
+    blocked_networks: Vec<IpNet>,
+}
+
+impl Default for DestinationPolicy {
+    fn default() -> Self {
+        DestinationPolicy::new(vec![], vec![], private_networks())
+    }
+}
+
+impl DestinationPolicy {
+    pub fn new(
+        allowed_hosts: Vec<HostPattern>,
+        denied_hosts: Vec<HostPattern>,
+        blocked_networks: Vec<IpNet>,
+    ) -> DestinationPolicy {
+        DestinationPolicy {
+            allowed_hosts,
+            denied_hosts,
+            blocked_networks,
+        }
+    }
+
+    pub fn from_env() -> Result<DestinationPolicy, Box<dyn Error>> {
+        let blocked_networks = if env_flag("ALLOW_PRIVATE_DESTINATIONS")? {
+            vec![]
+        } else {
+            private_networks()
+        };
+
+        Ok(DestinationPolicy::new(
+            host_patterns_from_env("DESTINATION_ALLOWED_HOSTS")?,
+            host_patterns_from_env("DESTINATION_DENIED_HOSTS")?,
+            blocked_networks,
+        ))
+    }
+
+    pub fn allows_host(&self, host: &str) -> bool {
+        (self.allowed_hosts.is_empty() || host_pattern::any_match(&self.allowed_hosts, host))
+            && !host_pattern::any_match(&self.denied_hosts, host)
+    }
+
+    // IPv6 addresses with an IPv4 one inside are held to that one too, so
+    // that 64:ff9b::7f00:1 can't reach 127.0.0.1 through a NAT64 gateway
+    pub fn allows_address(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        let blocked = |ip: IpAddr| self.blocked_networks.iter().any(|net| net.contains(&ip));
+        let embedded = match ip {
+            IpAddr::V6(ip) => embedded_ipv4(ip),
+            IpAddr::V4(_) => None,
+        };
+        !blocked(ip) && !embedded.is_some_and(|ip| blocked(IpAddr::V4(ip)))
+    }
+
+    // Checks what we can of a destination without resolving it - all there
+    // is to check when an upstream proxy does the resolving for us.
+    pub fn check_name(&self, host: &str) -> Result<(), String> {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if !self.allows_host(host) {
+            return Err(format!("{} is not an allowed destination", host));
+        }
+        match host.parse::<IpAddr>() {
+            Ok(ip) if !self.allows_address(ip) => Err(format!("{} is a private address", host)),
+            _ => Ok(()),
+        }
+    }
+
+    // Checks a destination before we fetch from it. Errors say why not.
+    //
+    // The resolved addresses are checked here so that we can show a helpful
+    // page, but a name could resolve differently by the time we connect - the
+    // client's GuardedResolver checks them again then. Addresses from DNS
+    // overrides are trusted, as someone configured them on purpose.
+    pub async fn check(&self, resolver: &DnsResolver, host: &str) -> Result<(), String> {
+        self.check_name(host)?;
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if self.blocked_networks.is_empty() || host.parse::<IpAddr>().is_ok() {
+            return Ok(());
+        }
+
+        // a name that doesn't resolve can't be connected to either, but a
+        // cached copy of the page might still be served
+        let Ok(resolved) = resolver.resolve(host).await else {
+            return Ok(());
+        };
+        if resolved.overridden {
+            return Ok(());
+        }
+        match resolved.addrs.iter().find(|ip| !self.allows_address(**ip)) {
+            Some(ip) => Err(format!("{} is a private address ({})", host, ip)),
+            None => Ok(()),
+        }
+    }
+}
+
+// The IPv4 address carried by NAT64 (64:ff9b::/96 and 64:ff9b:1::/48), 6to4
+// (2002::/16) and IPv4-compatible (::/96) addresses. IPv4-mapped ones are
+// already dealt with by to_canonical.
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let segments = ip.segments();
+    let octets = ip.octets();
+    let last_32 = Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15]);
+    match segments {
+        [0x64, 0xff9b, 0, 0, 0, 0, _, _] => Some(last_32),
+        [0x64, 0xff9b, 1, ..] => Some(last_32),
+        [0x2002, ..] => Some(Ipv4Addr::new(octets[2], octets[3], octets[4], octets[5])),
+        [0, 0, 0, 0, 0, 0, _, _] => Some(last_32),
+        _ => None,
+    }
+}
+
+fn private_networks() -> Vec<IpNet> {
+    PRIVATE_NETWORKS
+        .iter()
+        .map(|network| network.parse().unwrap())
+        .collect()
+}
+
+fn host_patterns_from_env(name: &str) -> Result<Vec<HostPattern>, Box<dyn Error>> {
+    env_list(name)?
+        .iter()
+        .map(|host| host.parse())
+        .collect::<Result<Vec<HostPattern>, String>>()
+        .map_err(|err| format!("{} was not valid: {}", name, err).into())
+}
+
+// Wraps the DNS resolver the upstream client uses, dropping any addresses
+// the policy blocks, so that a name can't be pointed somewhere private
+// between our check and the connection.
+#[derive(Clone)]
+pub struct GuardedResolver {
+    inner: DnsResolver,
+    policy: Arc<DestinationPolicy>,
+}
+
+impl GuardedResolver {
+    pub fn new(inner: DnsResolver, policy: Arc<DestinationPolicy>) -> GuardedResolver {
+        GuardedResolver { inner, policy }
+    }
+}
+
+type ResolveFuture =
+    Pin<Box<dyn Future<Output = Result<std::vec::IntoIter<SocketAddr>, io::Error>> + Send>>;
+
+impl Service<Name> for GuardedResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = io::Error;
+    type Future = ResolveFuture;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, name: Name) -> Self::Future {
+        let policy = self.policy.clone();
+        let resolver = self.inner.clone();
+        let host = name.as_str().to_string();
+        Box::pin(async move {
+            let resolved = resolver.resolve(&host).await?;
+            // the connector fills in the port
+            let addrs: Vec<SocketAddr> = resolved
+                .addrs
+                .into_iter()
+                .filter(|ip| resolved.overridden || policy.allows_address(*ip))
+                .map(|ip| SocketAddr::new(ip, 0))
+                .collect();
+            if addrs.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("{} only resolves to blocked addresses", host),
+                ));
+            }
+            Ok(addrs.into_iter())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns_resolver::{IpPreference, DEFAULT_CACHE_TTL};
+    use std::str::FromStr;
+
+    fn hosts(patterns: &[&str]) -> Vec<HostPattern> {
+        patterns.iter().map(|host| host.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn any_public_host_is_allowed_by_default() {
+        let policy = DestinationPolicy::default();
+        assert!(policy.allows_host("example.com"));
+        assert!(policy.allows_address("93.184.216.34".parse().unwrap()));
+    }
+
+    #[test]
+    fn private_addresses_are_blocked_by_default() {
+        let policy = DestinationPolicy::default();
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "::1",
+            "fe80::1",
+            "fd00::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!policy.allows_address(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn private_addresses_inside_ipv6_ones_are_blocked() {
+        let policy = DestinationPolicy::default();
+        for ip in [
+            // NAT64
+            "64:ff9b::7f00:1",
+            "64:ff9b::10.1.2.3",
+            "64:ff9b:1::a9fe:a9fe",
+            // 6to4
+            "2002:7f00:1::1",
+            "2002:c0a8:101::",
+            // IPv4-compatible
+            "::127.0.0.1",
+            "::10.1.2.3",
+        ] {
+            assert!(!policy.allows_address(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["64:ff9b::5db8:d822", "2002:5db8:d822::1", "::93.184.216.34"] {
+            assert!(policy.allows_address(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn deny_list_wins_over_allow_list() {
+        let policy = DestinationPolicy::new(
+            hosts(&["*.example.com"]),
+            hosts(&["admin.example.com"]),
+            vec![],
+        );
+        assert!(policy.allows_host("www.example.com"));
+        assert!(!policy.allows_host("admin.example.com"));
+        assert!(!policy.allows_host("example.org"));
+    }
+
+    #[tokio::test]
+    async fn check_refuses_private_ip_literals() {
+        let policy = DestinationPolicy::default();
+        let resolver = DnsResolver::default();
+        assert!(policy.check(&resolver, "169.254.169.254").await.is_err());
+        assert!(policy.check(&resolver, "[::1]").await.is_err());
+        assert!(policy.check(&resolver, "93.184.216.34").await.is_ok());
+    }
+
+    #[test]
+    fn check_name_refuses_private_ip_literals_without_resolving() {
+        let policy = DestinationPolicy::default();
+        assert!(policy.check_name("[fd00::1]").is_err());
+        assert!(policy.check_name("localhost").is_ok());
+    }
+
+    #[tokio::test]
+    async fn check_refuses_names_resolving_to_private_addresses() {
+        let policy = DestinationPolicy::default();
+        assert!(policy
+            .check(&DnsResolver::default(), "localhost")
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn check_trusts_overridden_names() {
+        let resolver = DnsResolver::new(
+            vec![(
+                "mirror.lab".parse().unwrap(),
+                "192.168.1.5".parse().unwrap(),
+            )],
+            vec![],
+            IpPreference::Any,
+            DEFAULT_CACHE_TTL,
+        );
+        let policy = DestinationPolicy::default();
+        assert!(policy.check(&resolver, "mirror.lab").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn check_allows_private_addresses_when_not_blocked() {
+        let policy = DestinationPolicy::new(vec![], vec![], vec![]);
+        assert!(policy
+            .check(&DnsResolver::default(), "localhost")
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn guarded_resolver_drops_blocked_addresses() {
+        let mut resolver = GuardedResolver::new(
+            DnsResolver::default(),
+            Arc::new(DestinationPolicy::default()),
+        );
+        let name = Name::from_str("localhost").unwrap();
+        let err = resolver.call(name).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    }
+}
@@ -0,0 +1,365 @@
+use crate::config::env_string;
+use crate::response_cache::CachedResponse;
+
+use blake2::{Blake2s256, Digest};
+use bytes::Bytes;
+use hyper::header::{HeaderMap, HeaderName, HeaderValue};
+use hyper::StatusCode;
+use std::error::Error;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// first line of every metadata file, so older or foreign files are skipped
+const FORMAT: &str = "the-insecure-proxy cache 1";
+
+// for naming temporary files uniquely within the process
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// Cached responses written to disk, so they outlast restarts and can stand
+// in for sites that can't be reached. Bodies are stored once each under the
+// hash of their contents in objects/. Each cached URL gets a directory in
+// entries/ with a metadata file per variant, naming the body it goes with.
+#[derive(Debug)]
+pub struct DiskCache {
+    dir: PathBuf,
+}
+
+impl DiskCache {
+    pub fn new(dir: PathBuf) -> DiskCache {
+        DiskCache { dir }
+    }
+
+    // None unless CACHE_DIR is set
+    pub fn from_env() -> Result<Option<DiskCache>, Box<dyn Error>> {
+        let Some(dir) = env_string("CACHE_DIR")? else {
+            return Ok(None);
+        };
+        let dir = PathBuf::from(dir);
+        std::fs::create_dir_all(&dir)
+            .map_err(|err| format!("couldn't create CACHE_DIR {}: {}", dir.display(), err))?;
+        println!("= Caching responses on disk in {}", dir.display());
+        Ok(Some(DiskCache::new(dir)))
+    }
+
+    fn entry_dir(&self, key: &str) -> PathBuf {
+        self.dir.join("entries").join(hash(key.as_bytes()))
+    }
+
+    fn object_path(&self, body_hash: &str) -> PathBuf {
+        self.dir
+            .join("objects")
+            .join(&body_hash[..2])
+            .join(body_hash)
+    }
+
+    // the stored variant of `key` that suits a request with these headers
+    pub async fn load(&self, key: &str, req_headers: &HeaderMap) -> Option<CachedResponse> {
+        let mut variants = tokio::fs::read_dir(self.entry_dir(key)).await.ok()?;
+        while let Ok(Some(variant)) = variants.next_entry().await {
+            let Ok(metadata) = tokio::fs::read(variant.path()).await else {
+                continue;
+            };
+            let Some((stored_key, body_hash, entry)) = decode(&metadata) else {
+                continue;
+            };
+            if stored_key != key || !entry.matches(req_headers) {
+                continue;
+            }
+            // a body that's gone missing or been damaged is as good as no
+            // entry at all
+            let body = tokio::fs::read(self.object_path(&body_hash)).await.ok()?;
+            if hash(&body) != body_hash {
+                return None;
+            }
+            return Some(CachedResponse {
+                body: Bytes::from(body),
+                ..entry
+            });
+        }
+        None
+    }
+
+    pub async fn save(&self, key: &str, entry: &CachedResponse) -> io::Result<()> {
+        let body_hash = hash(&entry.body);
+        let object = self.object_path(&body_hash);
+        if !tokio::fs::try_exists(&object).await? {
+            write_atomically(&object, &entry.body).await?;
+        }
+
+        let mut variant = Vec::new();
+        for (name, value) in &entry.vary {
+            variant.extend_from_slice(name.as_str().as_bytes());
+            variant.push(b'\n');
+            if let Some(value) = value {
+                variant.extend_from_slice(value.as_bytes());
+            }
+            variant.push(b'\n');
+        }
+        let path = self.entry_dir(key).join(hash(&variant));
+        write_atomically(&path, &encode(key, &body_hash, entry)).await
+    }
+}
+
+fn hash(bytes: &[u8]) -> String {
+    Blake2s256::digest(bytes)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+// so a crash (or another process reading) never sees half a file
+async fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let temp = path.with_extension(format!(
+        "tmp-{}-{}",
+        std::process::id(),
+        TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    tokio::fs::write(&temp, contents).await?;
+    tokio::fs::rename(&temp, path).await
+}
+
+fn unix_seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_secs()
+}
+
+// Metadata files are lines of "field value". Header values can't contain
+// line breaks, so they're written as they are.
+fn encode(key: &str, body_hash: &str, entry: &CachedResponse) -> Vec<u8> {
+    let mut out = format!("{}\n", FORMAT).into_bytes();
+    let mut line = |field: &str, value: &[u8]| {
+        out.extend_from_slice(field.as_bytes());
+        out.push(b' ');
+        out.extend_from_slice(value);
+        out.push(b'\n');
+    };
+    line("key", key.as_bytes());
+    line("body", body_hash.as_bytes());
+    line("status", entry.status.as_str().as_bytes());
+    let stored_at = unix_seconds(entry.stored_at).to_string();
+    line("stored-at", stored_at.as_bytes());
+    line(
+        "initial-age",
+        entry.initial_age.as_secs().to_string().as_bytes(),
+    );
+    line("lifetime", entry.lifetime.as_secs().to_string().as_bytes());
+    if let Some(etag) = &entry.etag {
+        line("etag", etag.as_bytes());
+    }
+    if let Some(last_modified) = &entry.last_modified {
+        line("last-modified", last_modified.as_bytes());
+    }
+    // a header that was missing has no space after its name
+    for (name, value) in &entry.vary {
+        let mut vary = name.as_str().as_bytes().to_vec();
+        if let Some(value) = value {
+            vary.push(b' ');
+            vary.extend_from_slice(value.as_bytes());
+        }
+        line("vary", &vary);
+    }
+    for (name, value) in &entry.headers {
+        let mut header = name.as_str().as_bytes().to_vec();
+        header.push(b' ');
+        header.extend_from_slice(value.as_bytes());
+        line("header", &header);
+    }
+    out
+}
+
+// the key, body hash and everything but the body
+fn decode(file: &[u8]) -> Option<(String, String, CachedResponse)> {
+    let mut lines = file.split(|byte| *byte == b'\n');
+    if lines.next()? != FORMAT.as_bytes() {
+        return None;
+    }
+
+    let mut key = None;
+    let mut body_hash = None;
+    let mut entry = CachedResponse {
+        status: StatusCode::OK,
+        headers: HeaderMap::new(),
+        body: Bytes::new(),
+        vary: vec![],
+        etag: None,
+        last_modified: None,
+        stored_at: UNIX_EPOCH,
+        initial_age: Duration::ZERO,
+        lifetime: Duration::ZERO,
+    };
+    let seconds = |value: &[u8]| {
+        Some(Duration::from_secs(
+            std::str::from_utf8(value).ok()?.parse().ok()?,
+        ))
+    };
+    for line in lines.filter(|line| !line.is_empty()) {
+        let (field, value) = split_once(line)?;
+        match field {
+            b"key" => key = Some(String::from_utf8(value.to_vec()).ok()?),
+            b"body" => body_hash = Some(String::from_utf8(value.to_vec()).ok()?),
+            b"status" => entry.status = StatusCode::from_bytes(value).ok()?,
+            b"stored-at" => entry.stored_at = UNIX_EPOCH + seconds(value)?,
+            b"initial-age" => entry.initial_age = seconds(value)?,
+            b"lifetime" => entry.lifetime = seconds(value)?,
+            b"etag" => entry.etag = Some(HeaderValue::from_bytes(value).ok()?),
+            b"last-modified" => entry.last_modified = Some(HeaderValue::from_bytes(value).ok()?),
+            b"vary" => {
+                let (name, value) = match split_once(value) {
+                    Some((name, value)) => (name, Some(HeaderValue::from_bytes(value).ok()?)),
+                    None => (value, None),
+                };
+                entry.vary.push((HeaderName::from_bytes(name).ok()?, value));
+            }
+            b"header" => {
+                let (name, value) = split_once(value)?;
+                entry.headers.append(
+                    HeaderName::from_bytes(name).ok()?,
+                    HeaderValue::from_bytes(value).ok()?,
+                );
+            }
+            _ => {}
+        }
+    }
+    let body_hash: String = body_hash?;
+    // it's used as a path
+    if body_hash.len() < 2 || !body_hash.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some((key?, body_hash, entry))
+}
+
+fn split_once(line: &[u8]) -> Option<(&[u8], &[u8])> {
+    let space = line.iter().position(|byte| *byte == b' ')?;
+    Some((&line[..space], &line[space + 1..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "default https://example.com/";
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "the-insecure-proxy-test-{}-{}",
+            std::process::id(),
+            TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn entry(body: &str, language: Option<&str>) -> CachedResponse {
+        let mut headers = HeaderMap::new();
+        headers.insert("content-type", "text/html".parse().unwrap());
+        headers.append("x-note", "one".parse().unwrap());
+        headers.append("x-note", "two".parse().unwrap());
+        CachedResponse {
+            status: StatusCode::OK,
+            headers,
+            body: Bytes::from(body.to_string()),
+            vary: vec![(
+                HeaderName::from_static("accept-language"),
+                language.map(|language| language.parse().unwrap()),
+            )],
+            etag: Some("\"v1\"".parse().unwrap()),
+            last_modified: None,
+            stored_at: UNIX_EPOCH + Duration::from_secs(1_000_000),
+            initial_age: Duration::from_secs(5),
+            lifetime: Duration::from_secs(60),
+        }
+    }
+
+    fn language(language: Option<&str>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        if let Some(language) = language {
+            headers.insert("accept-language", language.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn metadata_round_trips() {
+        let original = entry("hello", Some("en"));
+        let (key, body_hash, decoded) = decode(&encode(KEY, "abcd", &original)).unwrap();
+        assert_eq!(key, KEY);
+        assert_eq!(body_hash, "abcd");
+        assert_eq!(decoded.status, original.status);
+        assert_eq!(decoded.headers, original.headers);
+        assert_eq!(decoded.vary, original.vary);
+        assert_eq!(decoded.etag, original.etag);
+        assert_eq!(decoded.stored_at, original.stored_at);
+        assert_eq!(decoded.initial_age, original.initial_age);
+        assert_eq!(decoded.lifetime, original.lifetime);
+    }
+
+    #[test]
+    fn foreign_files_are_skipped() {
+        assert!(decode(b"something else\nkey x\n").is_none());
+        assert!(decode(format!("{}\nkey x\nbody ../../etc\n", FORMAT).as_bytes()).is_none());
+    }
+
+    #[tokio::test]
+    async fn variants_are_saved_and_found() {
+        let dir = temp_dir();
+        let cache = DiskCache::new(dir.clone());
+        cache.save(KEY, &entry("hello", Some("en"))).await.unwrap();
+        cache
+            .save(KEY, &entry("bonjour", Some("fr")))
+            .await
+            .unwrap();
+        cache.save(KEY, &entry("hi", None)).await.unwrap();
+
+        let body = |language: Option<&'static str>| {
+            let cache = &cache;
+            async move {
+                cache
+                    .load(KEY, &self::language(language))
+                    .await
+                    .map(|entry| entry.body)
+            }
+        };
+        assert_eq!(body(Some("en")).await, Some(Bytes::from("hello")));
+        assert_eq!(body(Some("fr")).await, Some(Bytes::from("bonjour")));
+        assert_eq!(body(None).await, Some(Bytes::from("hi")));
+        assert_eq!(body(Some("de")).await, None);
+        assert!(cache
+            .load("default https://example.com/other", &language(None))
+            .await
+            .is_none());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn identical_bodies_are_stored_once() {
+        let dir = temp_dir();
+        let cache = DiskCache::new(dir.clone());
+        cache.save(KEY, &entry("hello", Some("en"))).await.unwrap();
+        cache
+            .save(
+                "default https://example.com/copy",
+                &entry("hello", Some("en")),
+            )
+            .await
+            .unwrap();
+        let objects = std::fs::read_dir(dir.join("objects")).unwrap().count();
+        assert_eq!(objects, 1);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn damaged_bodies_are_not_served() {
+        let dir = temp_dir();
+        let cache = DiskCache::new(dir.clone());
+        cache.save(KEY, &entry("hello", None)).await.unwrap();
+        std::fs::write(cache.object_path(&hash(b"hello")), "goodbye").unwrap();
+        assert!(cache.load(KEY, &language(None)).await.is_none());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
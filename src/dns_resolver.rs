@@ -0,0 +1,391 @@
+use crate::config::{env_list, env_parse};
+use crate::host_pattern::HostPattern;
+
+use hickory_resolver::config::{
+    LookupIpStrategy, NameServerConfig, Protocol, ResolverConfig, ResolverOpts,
+};
+use hickory_resolver::TokioAsyncResolver;
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(60);
+
+// names cached before the cache is emptied and started again
+const MAX_CACHED: usize = 4096;
+
+// Which addresses to try first when a name has both kinds
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum IpPreference {
+    // whatever order the resolver gave
+    #[default]
+    Any,
+    Ipv4First,
+    Ipv6First,
+    Ipv4Only,
+    Ipv6Only,
+}
+
+impl IpPreference {
+    fn apply(self, mut addrs: Vec<IpAddr>) -> Vec<IpAddr> {
+        match self {
+            IpPreference::Any => {}
+            IpPreference::Ipv4First => addrs.sort_by_key(|ip| ip.is_ipv6()),
+            IpPreference::Ipv6First => addrs.sort_by_key(|ip| ip.is_ipv4()),
+            IpPreference::Ipv4Only => addrs.retain(|ip| ip.is_ipv4()),
+            IpPreference::Ipv6Only => addrs.retain(|ip| ip.is_ipv6()),
+        }
+        addrs
+    }
+}
+
+impl FromStr for IpPreference {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "any" => Ok(IpPreference::Any),
+            "ipv4-first" => Ok(IpPreference::Ipv4First),
+            "ipv6-first" => Ok(IpPreference::Ipv6First),
+            "ipv4-only" => Ok(IpPreference::Ipv4Only),
+            "ipv6-only" => Ok(IpPreference::Ipv6Only),
+            other => Err(format!("unknown IP preference {:?}", other)),
+        }
+    }
+}
+
+// "host=address", as written in DNS_OVERRIDES
+fn parse_override(s: &str) -> Result<(HostPattern, IpAddr), String> {
+    let (host, ip) = s
+        .split_once('=')
+        .ok_or_else(|| format!("{:?} should look like host=address", s))?;
+    let ip = ip
+        .trim()
+        .parse()
+        .map_err(|_| format!("{:?} is not an IP address", ip.trim()))?;
+    Ok((host.parse()?, ip))
+}
+
+// nameservers are written as an address, with an optional port
+fn parse_nameserver(s: &str) -> Result<SocketAddr, String> {
+    s.parse::<SocketAddr>()
+        .or_else(|_| s.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, 53)))
+        .map_err(|_| format!("{:?} is not a nameserver address", s))
+}
+
+// What a name resolved to. Overridden answers come from our own
+// configuration rather than DNS.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Resolved {
+    pub addrs: Vec<IpAddr>,
+    pub overridden: bool,
+}
+
+enum Backend {
+    // getaddrinfo, as configured for this machine
+    System,
+    Nameservers(Box<TokioAsyncResolver>),
+}
+
+struct Inner {
+    overrides: Vec<(HostPattern, Vec<IpAddr>)>,
+    backend: Backend,
+    preference: IpPreference,
+    cache_ttl: Duration,
+    // name -> (expiry, addresses)
+    cache: Mutex<HashMap<String, (Instant, Vec<IpAddr>)>>,
+}
+
+// Resolves the names of the origins we fetch from: static overrides first,
+// then the system resolver or our own list of nameservers, with answers
+// cached for a while.
+#[derive(Clone)]
+pub struct DnsResolver {
+    inner: Arc<Inner>,
+}
+
+impl Default for DnsResolver {
+    fn default() -> Self {
+        DnsResolver::new(vec![], vec![], IpPreference::Any, DEFAULT_CACHE_TTL)
+    }
+}
+
+impl DnsResolver {
+    pub fn new(
+        overrides: Vec<(HostPattern, IpAddr)>,
+        nameservers: Vec<SocketAddr>,
+        preference: IpPreference,
+        cache_ttl: Duration,
+    ) -> DnsResolver {
+        // several overrides for the same host give it several addresses
+        let mut grouped: Vec<(HostPattern, Vec<IpAddr>)> = vec![];
+        for (host, ip) in overrides {
+            match grouped.iter_mut().find(|(pattern, _)| *pattern == host) {
+                Some((_, addrs)) => addrs.push(ip),
+                None => grouped.push((host, vec![ip])),
+            }
+        }
+
+        let backend = if nameservers.is_empty() {
+            Backend::System
+        } else {
+            let configs: Vec<NameServerConfig> = nameservers
+                .iter()
+                .flat_map(|addr| {
+                    [
+                        NameServerConfig::new(*addr, Protocol::Udp),
+                        NameServerConfig::new(*addr, Protocol::Tcp),
+                    ]
+                })
+                .collect();
+            let mut options = ResolverOpts::default();
+            options.ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+            options.positive_max_ttl = Some(cache_ttl);
+            Backend::Nameservers(Box::new(TokioAsyncResolver::tokio(
+                ResolverConfig::from_parts(None, vec![], configs),
+                options,
+            )))
+        };
+
+        DnsResolver {
+            inner: Arc::new(Inner {
+                overrides: grouped,
+                backend,
+                preference,
+                cache_ttl,
+                cache: Mutex::new(HashMap::new()),
+            }),
+        }
+    }
+
+    pub fn from_env() -> Result<DnsResolver, Box<dyn Error>> {
+        let overrides = env_list("DNS_OVERRIDES")?
+            .iter()
+            .map(|entry| parse_override(entry))
+            .collect::<Result<Vec<_>, String>>()
+            .map_err(|err| format!("DNS_OVERRIDES was not valid: {}", err))?;
+        let nameservers = env_list("DNS_NAMESERVERS")?
+            .iter()
+            .map(|entry| parse_nameserver(entry))
+            .collect::<Result<Vec<_>, String>>()
+            .map_err(|err| format!("DNS_NAMESERVERS was not valid: {}", err))?;
+        let cache_ttl = env_parse("DNS_CACHE_TTL")?
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_CACHE_TTL);
+
+        Ok(DnsResolver::new(
+            overrides,
+            nameservers,
+            env_parse("DNS_IP_PREFERENCE")?.unwrap_or_default(),
+            cache_ttl,
+        ))
+    }
+
+    pub async fn resolve(&self, host: &str) -> io::Result<Resolved> {
+        let host = host
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_ascii_lowercase();
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(Resolved {
+                addrs: vec![ip],
+                overridden: false,
+            });
+        }
+        if let Some((_, addrs)) = self
+            .inner
+            .overrides
+            .iter()
+            .find(|(pattern, _)| pattern.matches(&host))
+        {
+            return Ok(Resolved {
+                addrs: addrs.clone(),
+                overridden: true,
+            });
+        }
+
+        if let Some((expiry, addrs)) = self.inner.cache.lock().unwrap().get(&host) {
+            if *expiry > Instant::now() {
+                return Ok(Resolved {
+                    addrs: addrs.clone(),
+                    overridden: false,
+                });
+            }
+        }
+
+        let (addrs, expiry) = self.lookup(&host).await?;
+        let addrs = self.inner.preference.apply(addrs);
+        if addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} has no usable addresses", host),
+            ));
+        }
+
+        let expiry = expiry.min(Instant::now() + self.inner.cache_ttl);
+        if expiry > Instant::now() {
+            let mut cache = self.inner.cache.lock().unwrap();
+            if cache.len() >= MAX_CACHED {
+                cache.clear();
+            }
+            cache.insert(host, (expiry, addrs.clone()));
+        }
+        Ok(Resolved {
+            addrs,
+            overridden: false,
+        })
+    }
+
+    // the addresses and when the answer runs out
+    async fn lookup(&self, host: &str) -> io::Result<(Vec<IpAddr>, Instant)> {
+        match &self.inner.backend {
+            Backend::System => {
+                let addrs = tokio::net::lookup_host((host, 0))
+                    .await?
+                    .map(|addr| addr.ip())
+                    .collect();
+                Ok((addrs, Instant::now() + self.inner.cache_ttl))
+            }
+            Backend::Nameservers(resolver) => {
+                let lookup = resolver
+                    .lookup_ip(host)
+                    .await
+                    .map_err(|err| io::Error::new(io::ErrorKind::NotFound, err.to_string()))?;
+                Ok((lookup.iter().collect(), lookup.valid_until()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hickory_resolver::proto::op::{Message, MessageType};
+    use hickory_resolver::proto::rr::rdata::A;
+    use hickory_resolver::proto::rr::{RData, Record, RecordType};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::net::UdpSocket;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn preference_orders_and_filters() {
+        let addrs = vec![ip("2001:db8::1"), ip("192.0.2.1")];
+        assert_eq!(
+            IpPreference::Ipv4First.apply(addrs.clone()),
+            vec![ip("192.0.2.1"), ip("2001:db8::1")]
+        );
+        assert_eq!(
+            IpPreference::Ipv6Only.apply(addrs.clone()),
+            vec![ip("2001:db8::1")]
+        );
+        assert_eq!(IpPreference::Any.apply(addrs.clone()), addrs);
+        assert!("ipv5-only".parse::<IpPreference>().is_err());
+    }
+
+    #[test]
+    fn parse_settings() {
+        assert_eq!(
+            parse_override("mirror.lab = 192.168.1.5"),
+            Ok(("mirror.lab".parse().unwrap(), ip("192.168.1.5")))
+        );
+        assert!(parse_override("mirror.lab").is_err());
+        assert_eq!(
+            parse_nameserver("9.9.9.9"),
+            Ok("9.9.9.9:53".parse().unwrap())
+        );
+        assert_eq!(
+            parse_nameserver("[::1]:5353"),
+            Ok("[::1]:5353".parse().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn overrides_win() {
+        let resolver = DnsResolver::new(
+            vec![
+                ("*.example.com".parse().unwrap(), ip("192.168.1.5")),
+                ("*.example.com".parse().unwrap(), ip("192.168.1.6")),
+            ],
+            vec![],
+            IpPreference::Any,
+            DEFAULT_CACHE_TTL,
+        );
+        assert_eq!(
+            resolver.resolve("www.Example.com").await.unwrap(),
+            Resolved {
+                addrs: vec![ip("192.168.1.5"), ip("192.168.1.6")],
+                overridden: true,
+            }
+        );
+        assert!(!resolver.resolve("localhost").await.unwrap().overridden);
+    }
+
+    // a nameserver that answers every A query with 192.0.2.7, counting them
+    async fn start_nameserver() -> (SocketAddr, Arc<AtomicUsize>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        let queries = Arc::new(AtomicUsize::new(0));
+        let counter = queries.clone();
+        tokio::task::spawn(async move {
+            let mut buf = [0u8; 512];
+            loop {
+                let (len, peer) = socket.recv_from(&mut buf).await.unwrap();
+                let query = Message::from_vec(&buf[..len]).unwrap();
+                let mut response = Message::new();
+                response
+                    .set_id(query.id())
+                    .set_message_type(MessageType::Response)
+                    .set_recursion_desired(true)
+                    .set_recursion_available(true)
+                    .add_queries(query.queries().to_vec());
+                for question in query.queries() {
+                    if question.query_type() == RecordType::A {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                        response.add_answer(Record::from_rdata(
+                            question.name().clone(),
+                            300,
+                            RData::A(A::new(192, 0, 2, 7)),
+                        ));
+                    }
+                }
+                socket
+                    .send_to(&response.to_vec().unwrap(), peer)
+                    .await
+                    .unwrap();
+            }
+        });
+        (addr, queries)
+    }
+
+    #[tokio::test]
+    async fn uses_configured_nameservers_and_caches() {
+        let (nameserver, queries) = start_nameserver().await;
+        let resolver = DnsResolver::new(
+            vec![],
+            vec![nameserver],
+            IpPreference::Ipv4Only,
+            DEFAULT_CACHE_TTL,
+        );
+        for _ in 0..2 {
+            assert_eq!(
+                resolver.resolve("www.example.com").await.unwrap().addrs,
+                vec![ip("192.0.2.7")]
+            );
+        }
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn zero_ttl_turns_off_the_cache() {
+        let resolver = DnsResolver::new(vec![], vec![], IpPreference::Any, Duration::ZERO);
+        resolver.resolve("localhost").await.unwrap();
+        assert!(resolver.inner.cache.lock().unwrap().is_empty());
+    }
+}
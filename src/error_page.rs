@@ -0,0 +1,51 @@
+use crate::html::escape_attribute;
+
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::http::HeaderValue;
+use hyper::{Response, StatusCode};
+
+// An HTML page explaining why we didn't fetch what was asked for. Kept to
+// HTML 2.0 so that every browser that might be on the other end can show it.
+pub fn response(status: StatusCode, message: &str) -> Response<Full<Bytes>> {
+    let title = format!(
+        "{} {}",
+        status.as_u16(),
+        status.canonical_reason().unwrap_or("Error")
+    );
+    let body = format!(
+        concat!(
+            "<html><head><title>{title}</title></head>\n",
+            "<body><h1>{title}</h1>\n",
+            "<p>{message}</p>\n",
+            "<hr><address>The Insecure Proxy</address></body></html>\n"
+        ),
+        title = title,
+        message = escape_attribute(message),
+    );
+
+    let mut response = Response::new(Full::new(Bytes::from(body)));
+    *response.status_mut() = status;
+    response
+        .headers_mut()
+        .insert("Content-Type", HeaderValue::from_static("text/html"));
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http_body_util::BodyExt;
+
+    #[tokio::test]
+    async fn page_has_status_and_escaped_message() {
+        let response = response(StatusCode::FORBIDDEN, "No <b>entry</b>");
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(response.headers()["Content-Type"], "text/html");
+
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        assert!(body.contains("<title>403 Forbidden</title>"));
+        assert!(body.contains("No &lt;b&gt;entry&lt;/b&gt;"));
+    }
+}
@@ -0,0 +1,296 @@
+use crate::client_profile::parse_network;
+use crate::config::{env_list, env_parse};
+
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, FORWARDED, HOST};
+use ipnet::IpNet;
+use std::error::Error;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+
+// Which headers tell origins who a request came from
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum Style {
+    // none - requests go out with whatever the client sent
+    #[default]
+    Off,
+    // RFC 7239's Forwarded
+    Forwarded,
+    // the older X-Forwarded-For, X-Forwarded-Proto and X-Forwarded-Host
+    XForwarded,
+}
+
+impl FromStr for Style {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "off" => Ok(Style::Off),
+            "forwarded" => Ok(Style::Forwarded),
+            "x-forwarded" => Ok(Style::XForwarded),
+            other => Err(format!("unknown forwarded header style {:?}", other)),
+        }
+    }
+}
+
+// What happens to forwarding headers a request arrives with
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum Existing {
+    // thrown away - anyone can claim anything in them
+    #[default]
+    Strip,
+    // kept, with us added on the end
+    Append,
+    // kept from trusted peers, like a load balancer in front of us, and
+    // thrown away from everyone else
+    Trust,
+}
+
+impl FromStr for Existing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "strip" => Ok(Existing::Strip),
+            "append" => Ok(Existing::Append),
+            "trust" => Ok(Existing::Trust),
+            other => Err(format!(
+                "unknown way of handling forwarded headers {:?}",
+                other
+            )),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct ForwardedHeaders {
+    style: Style,
+    existing: Existing,
+    trusted_peers: Vec<IpNet>,
+}
+
+impl ForwardedHeaders {
+    pub fn new(style: Style, existing: Existing, trusted_peers: Vec<IpNet>) -> ForwardedHeaders {
+        ForwardedHeaders {
+            style,
+            existing,
+            trusted_peers,
+        }
+    }
+
+    pub fn from_env() -> Result<ForwardedHeaders, Box<dyn Error>> {
+        let trusted_peers = env_list("FORWARDED_TRUSTED_PEERS")?
+            .iter()
+            .map(|network| parse_network(network))
+            .collect::<Result<Vec<IpNet>, String>>()
+            .map_err(|err| format!("FORWARDED_TRUSTED_PEERS was not valid: {}", err))?;
+        Ok(ForwardedHeaders::new(
+            env_parse("FORWARDED_HEADERS")?.unwrap_or_default(),
+            env_parse("FORWARDED_EXISTING")?.unwrap_or_default(),
+            trusted_peers,
+        ))
+    }
+
+    fn keeps_existing(&self, client: IpAddr) -> bool {
+        match self.existing {
+            Existing::Strip => false,
+            Existing::Append => true,
+            Existing::Trust => {
+                let client = client.to_canonical();
+                self.trusted_peers.iter().any(|net| net.contains(&client))
+            }
+        }
+    }
+
+    // Adds our hop - the client's address, and whether it reached us over
+    // http or https - to a request on its way to the origin
+    pub fn apply(&self, headers: &mut HeaderMap, client: IpAddr, proto: &str) {
+        if self.style == Style::Off {
+            return;
+        }
+        if !self.keeps_existing(client) {
+            for name in [
+                FORWARDED,
+                X_FORWARDED_FOR,
+                X_FORWARDED_PROTO,
+                X_FORWARDED_HOST,
+            ] {
+                headers.remove(name);
+            }
+        }
+        let client = client.to_canonical();
+        let host = headers
+            .get(HOST)
+            .and_then(|host| host.to_str().ok())
+            .map(String::from);
+
+        match self.style {
+            Style::Off => {}
+            Style::Forwarded => {
+                let node = match client {
+                    IpAddr::V4(addr) => addr.to_string(),
+                    IpAddr::V6(addr) => format!("\"[{}]\"", addr),
+                };
+                let mut element = format!("for={};proto={}", node, proto);
+                if let Some(host) = host {
+                    element.push_str(&format!(";host={}", quoted(&host)));
+                }
+                append(headers, FORWARDED, &element);
+            }
+            Style::XForwarded => {
+                append(headers, X_FORWARDED_FOR, &client.to_string());
+                // the first proxy saw what the browser asked for, so what
+                // it says wins
+                if !headers.contains_key(X_FORWARDED_PROTO) {
+                    headers.insert(X_FORWARDED_PROTO, HeaderValue::from_str(proto).unwrap());
+                }
+                if let Some(host) = host.and_then(|host| HeaderValue::from_str(&host).ok()) {
+                    headers.entry(X_FORWARDED_HOST).or_insert(host);
+                }
+            }
+        }
+    }
+}
+
+// adds value to the end of a comma-separated list, folding any separate
+// lines of it into one
+fn append(headers: &mut HeaderMap, name: HeaderName, value: &str) {
+    let mut values: Vec<&str> = headers
+        .get_all(&name)
+        .iter()
+        .filter_map(|existing| existing.to_str().ok())
+        .collect();
+    values.push(value);
+    if let Ok(joined) = HeaderValue::from_str(&values.join(", ")) {
+        headers.insert(name, joined);
+    }
+}
+
+// a Forwarded value as a token if it can be one, or a quoted string if not
+fn quoted(value: &str) -> String {
+    let is_token = !value.is_empty()
+        && value
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte));
+    if is_token {
+        return value.to_string();
+    }
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn forwarded(style: Style, existing: Existing, trusted: &[&str]) -> ForwardedHeaders {
+        ForwardedHeaders::new(
+            style,
+            existing,
+            trusted.iter().map(|net| net.parse().unwrap()).collect(),
+        )
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    const SPOOFED: &[(&str, &str)] = &[
+        ("Host", "example.com"),
+        ("Forwarded", "for=10.9.9.9"),
+        ("X-Forwarded-For", "10.9.9.9"),
+        ("X-Forwarded-Proto", "https"),
+        ("X-Forwarded-Host", "elsewhere.com"),
+    ];
+
+    #[test]
+    fn off_leaves_requests_alone() {
+        let mut headers = headers(SPOOFED);
+        ForwardedHeaders::default().apply(&mut headers, "192.0.2.1".parse().unwrap(), "http");
+        assert_eq!(headers, self::headers(SPOOFED));
+    }
+
+    #[test]
+    fn forwarded_describes_the_client() {
+        let mut headers = headers(&[("Host", "example.com:8080")]);
+        forwarded(Style::Forwarded, Existing::Strip, &[]).apply(
+            &mut headers,
+            "2001:db8::1".parse().unwrap(),
+            "https",
+        );
+        assert_eq!(
+            headers[FORWARDED],
+            "for=\"[2001:db8::1]\";proto=https;host=\"example.com:8080\""
+        );
+    }
+
+    #[test]
+    fn strip_replaces_what_the_client_said() {
+        let mut headers = headers(SPOOFED);
+        forwarded(Style::XForwarded, Existing::Strip, &[]).apply(
+            &mut headers,
+            "::ffff:192.0.2.1".parse().unwrap(),
+            "http",
+        );
+        assert!(!headers.contains_key(FORWARDED));
+        assert_eq!(headers[X_FORWARDED_FOR], "192.0.2.1");
+        assert_eq!(headers[X_FORWARDED_PROTO], "http");
+        assert_eq!(headers[X_FORWARDED_HOST], "example.com");
+    }
+
+    #[test]
+    fn append_adds_to_the_chain() {
+        let mut headers = headers(SPOOFED);
+        headers.append("X-Forwarded-For", HeaderValue::from_static("10.8.8.8"));
+        forwarded(Style::XForwarded, Existing::Append, &[]).apply(
+            &mut headers,
+            "192.0.2.1".parse().unwrap(),
+            "http",
+        );
+        assert_eq!(headers[X_FORWARDED_FOR], "10.9.9.9, 10.8.8.8, 192.0.2.1");
+        assert_eq!(headers[X_FORWARDED_PROTO], "https");
+        assert_eq!(headers[X_FORWARDED_HOST], "elsewhere.com");
+
+        let mut headers = self::headers(SPOOFED);
+        forwarded(Style::Forwarded, Existing::Append, &[]).apply(
+            &mut headers,
+            "192.0.2.1".parse().unwrap(),
+            "http",
+        );
+        assert_eq!(
+            headers[FORWARDED],
+            "for=10.9.9.9, for=192.0.2.1;proto=http;host=example.com"
+        );
+    }
+
+    #[test]
+    fn trust_only_keeps_what_trusted_peers_said() {
+        let policy = forwarded(Style::XForwarded, Existing::Trust, &["10.0.0.0/8"]);
+
+        let mut headers = headers(SPOOFED);
+        policy.apply(&mut headers, "10.1.2.3".parse().unwrap(), "http");
+        assert_eq!(headers[X_FORWARDED_FOR], "10.9.9.9, 10.1.2.3");
+        assert_eq!(headers[X_FORWARDED_PROTO], "https");
+
+        let mut headers = self::headers(SPOOFED);
+        policy.apply(&mut headers, "192.0.2.1".parse().unwrap(), "http");
+        assert_eq!(headers[X_FORWARDED_FOR], "192.0.2.1");
+        assert_eq!(headers[X_FORWARDED_PROTO], "http");
+        assert!(!headers.contains_key(FORWARDED));
+    }
+
+    #[test]
+    fn parses_settings() {
+        assert_eq!("X-Forwarded".parse(), Ok(Style::XForwarded));
+        assert_eq!("forwarded".parse(), Ok(Style::Forwarded));
+        assert!("both".parse::<Style>().is_err());
+        assert_eq!(" trust ".parse(), Ok(Existing::Trust));
+        assert!("keep".parse::<Existing>().is_err());
+    }
+}
@@ -0,0 +1,165 @@
+use crate::config::{env_flag, env_string};
+
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, CONNECTION, VIA};
+use hyper::Version;
+use std::error::Error;
+
+pub const DEFAULT_VIA_PSEUDONYM: &str = "the-insecure-proxy";
+
+// Headers that are about one connection - between the client and us, or us
+// and the origin - rather than the message, so they stop here (RFC 9110
+// section 7.6.1). Proxy-Connection never made it into a standard but old
+// browsers still send it.
+const HOP_BY_HOP: &[&str] = &[
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+// Takes the hop-by-hop headers out of a message we're about to pass on,
+// along with any others its Connection header names
+pub fn remove(headers: &mut HeaderMap) {
+    let named: Vec<HeaderName> = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+    for name in named {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP {
+        headers.remove(*name);
+    }
+}
+
+// The Via entry we add to messages on their way through, if we add one. The
+// pseudonym can have a port on the end, like a host name.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Via {
+    pseudonym: Option<String>,
+}
+
+impl Via {
+    pub fn new(pseudonym: Option<String>) -> Result<Via, String> {
+        match &pseudonym {
+            Some(name) if !is_pseudonym(name) => {
+                Err(format!("{:?} can't go in a Via header", name))
+            }
+            _ => Ok(Via { pseudonym }),
+        }
+    }
+
+    pub fn from_env() -> Result<Via, Box<dyn Error>> {
+        if !env_flag("ADD_VIA_HEADER")? {
+            return Ok(Via::default());
+        }
+        let pseudonym =
+            env_string("VIA_PSEUDONYM")?.unwrap_or_else(|| DEFAULT_VIA_PSEUDONYM.to_string());
+        Via::new(Some(pseudonym.trim().to_string()))
+            .map_err(|err| format!("VIA_PSEUDONYM was not valid: {}", err).into())
+    }
+
+    // adds us to the end of the chain, saying which version of HTTP the
+    // message came to us over
+    pub fn add(&self, headers: &mut HeaderMap, version: Version) {
+        let Some(pseudonym) = &self.pseudonym else {
+            return;
+        };
+        let protocol = match version {
+            Version::HTTP_09 => "0.9",
+            Version::HTTP_10 => "1.0",
+            Version::HTTP_2 => "2",
+            Version::HTTP_3 => "3",
+            _ => "1.1",
+        };
+        let entry = format!("{} {}", protocol, pseudonym);
+        headers.append(VIA, HeaderValue::from_str(&entry).unwrap());
+    }
+}
+
+// a token, or a host and port
+fn is_pseudonym(name: &str) -> bool {
+    let separator = |byte: u8| b"\"(),/;<=>?@[\\]{}".contains(&byte);
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|byte| byte.is_ascii_graphic() && !separator(byte))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn removes_hop_by_hop_headers() {
+        let mut headers = headers(&[
+            ("Connection", "keep-alive"),
+            ("Keep-Alive", "timeout=5"),
+            ("Proxy-Connection", "keep-alive"),
+            ("TE", "trailers"),
+            ("Trailer", "Expires"),
+            ("Transfer-Encoding", "chunked"),
+            ("Upgrade", "h2c"),
+            ("Content-Type", "text/html"),
+            ("Cache-Control", "no-cache"),
+        ]);
+        remove(&mut headers);
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers["Content-Type"], "text/html");
+        assert_eq!(headers["Cache-Control"], "no-cache");
+    }
+
+    #[test]
+    fn removes_headers_named_in_connection() {
+        let mut headers = headers(&[
+            ("Connection", "close, X-Session"),
+            ("Connection", "x-trace"),
+            ("X-Session", "abc"),
+            ("X-Trace", "123"),
+            ("X-Other", "kept"),
+        ]);
+        remove(&mut headers);
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers["X-Other"], "kept");
+    }
+
+    #[test]
+    fn via_is_off_by_default() {
+        let mut headers = HeaderMap::new();
+        Via::default().add(&mut headers, Version::HTTP_11);
+        assert!(headers.is_empty());
+    }
+
+    #[test]
+    fn via_names_the_protocol_it_came_over() {
+        let via = Via::new(Some("proxy".to_string())).unwrap();
+        let mut headers = headers(&[("Via", "1.1 upstream")]);
+        via.add(&mut headers, Version::HTTP_10);
+        via.add(&mut headers, Version::HTTP_2);
+        let entries: Vec<_> = headers.get_all(VIA).iter().collect();
+        assert_eq!(entries, ["1.1 upstream", "1.0 proxy", "2 proxy"]);
+    }
+
+    #[test]
+    fn via_pseudonym_has_to_be_a_token() {
+        assert!(Via::new(Some("my-proxy.lan".to_string())).is_ok());
+        assert!(Via::new(Some("my-proxy.lan:3080".to_string())).is_ok());
+        assert!(Via::new(Some("my proxy".to_string())).is_err());
+        assert!(Via::new(Some("a,b".to_string())).is_err());
+        assert!(Via::new(Some(String::new())).is_err());
+    }
+}
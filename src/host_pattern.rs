@@ -0,0 +1,104 @@
+use std::fmt;
+use std::str::FromStr;
+
+// A hostname to match against, as written in configuration: "example.com"
+// matches just that host, "*.example.com" matches any subdomain of it (but
+// not example.com itself), and "*" matches everything.
+#[derive(Debug, PartialEq, Clone)]
+pub enum HostPattern {
+    Any,
+    Exact(String),
+    Subdomains(String),
+}
+
+impl HostPattern {
+    pub fn matches(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        match self {
+            HostPattern::Any => true,
+            HostPattern::Exact(name) => host == *name,
+            HostPattern::Subdomains(parent) => host
+                .strip_suffix(parent.as_str())
+                .is_some_and(|prefix| prefix.ends_with('.') && prefix.len() > 1),
+        }
+    }
+}
+
+pub fn any_match(patterns: &[HostPattern], host: &str) -> bool {
+    patterns.iter().any(|pattern| pattern.matches(host))
+}
+
+impl FromStr for HostPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let pattern = s.trim().trim_end_matches('.').to_ascii_lowercase();
+        if pattern == "*" {
+            return Ok(HostPattern::Any);
+        }
+
+        let (pattern, subdomains) = match pattern.strip_prefix("*.") {
+            Some(parent) => (parent.to_string(), true),
+            None => (pattern, false),
+        };
+        if pattern.is_empty() || pattern.contains(['*', '/', ':', ' ']) {
+            return Err(format!("{:?} is not a valid host pattern", s));
+        }
+
+        if subdomains {
+            Ok(HostPattern::Subdomains(pattern))
+        } else {
+            Ok(HostPattern::Exact(pattern))
+        }
+    }
+}
+
+impl fmt::Display for HostPattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HostPattern::Any => write!(f, "*"),
+            HostPattern::Exact(name) => write!(f, "{}", name),
+            HostPattern::Subdomains(parent) => write!(f, "*.{}", parent),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pattern(s: &str) -> HostPattern {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn exact_matches_ignoring_case_and_trailing_dot() {
+        assert!(pattern("Example.com").matches("example.COM."));
+        assert!(!pattern("example.com").matches("www.example.com"));
+    }
+
+    #[test]
+    fn wildcard_matches_subdomains_only() {
+        assert!(pattern("*.example.com").matches("www.example.com"));
+        assert!(pattern("*.example.com").matches("a.b.example.com"));
+        assert!(!pattern("*.example.com").matches("example.com"));
+        assert!(!pattern("*.example.com").matches("badexample.com"));
+    }
+
+    #[test]
+    fn star_matches_everything() {
+        assert!(pattern("*").matches("anything.at.all"));
+    }
+
+    #[test]
+    fn invalid_patterns() {
+        assert!("".parse::<HostPattern>().is_err());
+        assert!("www.*.com".parse::<HostPattern>().is_err());
+        assert!("example.com:443".parse::<HostPattern>().is_err());
+    }
+
+    #[test]
+    fn display_round_trips() {
+        assert_eq!(pattern("*.Example.com").to_string(), "*.example.com");
+    }
+}
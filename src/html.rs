@@ -0,0 +1,84 @@
+use crate::content_type;
+
+use bytes::Bytes;
+use encoding_rs::Encoding;
+use lol_html::{AsciiCompatibleEncoding, ElementContentHandlers, HtmlRewriter, Selector, Settings};
+use std::borrow::Cow;
+use std::error::Error;
+
+pub type ElementHandlers<'h, 's> = Vec<(Cow<'s, Selector>, ElementContentHandlers<'h>)>;
+
+// Runs an HTML body through lol_html with the given handlers. The output is in
+// the same charset as the input - taken from the Content-Type or a <meta>
+// charset, defaulting to UTF-8.
+pub fn rewrite(
+    body: &[u8],
+    content_type: &str,
+    element_content_handlers: ElementHandlers,
+) -> Result<Bytes, Box<dyn Error>> {
+    let encoding = match content_type::charset(content_type) {
+        Some(label) => Encoding::for_label(label.as_bytes())
+            .and_then(AsciiCompatibleEncoding::new)
+            .ok_or_else(|| format!("can't rewrite HTML in charset {:?}", label))?,
+        None => AsciiCompatibleEncoding::utf_8(),
+    };
+
+    let mut output = Vec::with_capacity(body.len());
+    let mut rewriter = HtmlRewriter::new(
+        Settings {
+            element_content_handlers,
+            encoding,
+            adjust_charset_on_meta_tag: true,
+            ..Settings::new()
+        },
+        |chunk: &[u8]| output.extend_from_slice(chunk),
+    );
+    rewriter.write(body)?;
+    rewriter.end()?;
+
+    Ok(Bytes::from(output))
+}
+
+pub fn is_html(content_type: &str) -> bool {
+    content_type::is(content_type, "text/html")
+        || content_type::is(content_type, "application/xhtml+xml")
+}
+
+pub fn escape_attribute(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lol_html::element;
+
+    #[test]
+    fn rewrite_keeps_input_charset() {
+        let output = rewrite(
+            b"<p title=\"caf\xe9\">caf\xe9</p>",
+            "text/html; charset=windows-1252",
+            vec![element!("p", |el| {
+                el.set_attribute("class", "x")?;
+                Ok(())
+            })],
+        )
+        .unwrap();
+
+        assert_eq!(&output[..], b"<p title=\"caf\xe9\" class=\"x\">caf\xe9</p>");
+    }
+
+    #[test]
+    fn rewrite_refuses_utf16() {
+        assert!(rewrite(b"", "text/html; charset=utf-16", vec![]).is_err());
+    }
+
+    #[test]
+    fn escape_attribute_escapes_quotes_and_brackets() {
+        assert_eq!(escape_attribute("a&b\"<c>"), "a&amp;b&quot;&lt;c&gt;");
+    }
+}
@@ -0,0 +1,207 @@
+use crate::html::{self, escape_attribute};
+
+use bytes::Bytes;
+use lol_html::html_content::ContentType;
+use lol_html::{element, text};
+use std::cell::RefCell;
+use std::error::Error;
+
+pub const DEFAULT_MAX_INLINE_STYLE_LENGTH: usize = 1024;
+
+// elements that are either useless without JavaScript or that old browsers
+// would render as a pile of garbage text
+const REMOVED_ELEMENTS: &str = "script, svg, math, template, video source, video track, audio source, audio track, picture source";
+
+// HTML5 sectioning elements, which old browsers don't know. they'd still show
+// the content inline, but turning them into divs keeps the line breaks.
+const SECTIONING_ELEMENTS: &str =
+    "header, footer, nav, section, article, aside, main, figure, figcaption";
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct HtmlSimplification {
+    // <style> blocks and style attributes longer than this are dropped
+    pub max_inline_style_length: usize,
+}
+
+// Strips an HTML page down to something HTML 3.2/4-era browsers can cope
+// with: no scripts, no event handlers, no SVG, no video, and no enormous
+// stylesheets.
+pub fn simplify(
+    body: &[u8],
+    content_type: &str,
+    options: &HtmlSimplification,
+) -> Result<Bytes, Box<dyn Error>> {
+    let max_style = options.max_inline_style_length;
+    let style_buffer = RefCell::new(String::new());
+
+    html::rewrite(
+        body,
+        content_type,
+        vec![
+            element!(REMOVED_ELEMENTS, |el| {
+                el.remove();
+                Ok(())
+            }),
+            // old browsers don't run scripts anyway, so they want what's inside
+            element!("noscript, picture", |el| {
+                el.remove_and_keep_content();
+                Ok(())
+            }),
+            element!(SECTIONING_ELEMENTS, |el| {
+                el.set_tag_name("div")?;
+                Ok(())
+            }),
+            element!("video, audio", |el| {
+                match el.get_attribute("poster") {
+                    Some(poster) => el.replace(
+                        &format!("<img src=\"{}\" alt=\"\">", escape_attribute(&poster)),
+                        ContentType::Html,
+                    ),
+                    None => el.remove_and_keep_content(),
+                }
+                Ok(())
+            }),
+            element!("*", |el| {
+                let handlers: Vec<String> = el
+                    .attributes()
+                    .iter()
+                    .map(|attr| attr.name())
+                    .filter(|name| name.starts_with("on"))
+                    .collect();
+                for name in handlers {
+                    el.remove_attribute(&name);
+                }
+                if el
+                    .get_attribute("style")
+                    .is_some_and(|style| style.len() > max_style)
+                {
+                    el.remove_attribute("style");
+                }
+                Ok(())
+            }),
+            // we can't know how long a stylesheet is until we've seen all of
+            // it, so hold the text back and only put it out at the end if it
+            // turned out to be small enough
+            text!("style", |chunk| {
+                let mut buffer = style_buffer.borrow_mut();
+                buffer.push_str(chunk.as_str());
+                if chunk.last_in_text_node() {
+                    let css = std::mem::take(&mut *buffer);
+                    chunk.set_str(if css.len() > max_style {
+                        String::new()
+                    } else {
+                        css
+                    });
+                } else {
+                    chunk.set_str(String::new());
+                }
+                Ok(())
+            }),
+        ],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn simplify_str(html: &str) -> String {
+        let options = HtmlSimplification {
+            max_inline_style_length: 20,
+        };
+        let output = simplify(html.as_bytes(), "text/html", &options).unwrap();
+        String::from_utf8(output.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn removes_scripts() {
+        assert_eq!(
+            simplify_str("<p>a</p><script>alert('<p>')</script><p>b</p>"),
+            "<p>a</p><p>b</p>"
+        );
+    }
+
+    #[test]
+    fn unwraps_noscript() {
+        assert_eq!(
+            simplify_str("<noscript><img src=\"a.gif\"></noscript>"),
+            "<img src=\"a.gif\">"
+        );
+    }
+
+    #[test]
+    fn removes_event_handlers() {
+        assert_eq!(
+            simplify_str("<body onload=\"go()\" class=\"x\"><a href=\"/\" onclick=\"no()\">hi</a>"),
+            "<body class=\"x\"><a href=\"/\">hi</a>"
+        );
+    }
+
+    #[test]
+    fn removes_svg() {
+        assert_eq!(
+            simplify_str("<p><svg viewBox=\"0 0 1 1\"><path d=\"M0\"/></svg>icon</p>"),
+            "<p>icon</p>"
+        );
+    }
+
+    #[test]
+    fn picture_falls_back_to_img() {
+        assert_eq!(
+            simplify_str(
+                "<picture><source srcset=\"a.avif\" type=\"image/avif\"><img src=\"a.jpg\"></picture>"
+            ),
+            "<img src=\"a.jpg\">"
+        );
+    }
+
+    #[test]
+    fn video_with_poster_becomes_img() {
+        assert_eq!(
+            simplify_str("<video poster=\"p.jpg\"><source src=\"v.mp4\">Old browser</video>"),
+            "<img src=\"p.jpg\" alt=\"\">"
+        );
+    }
+
+    #[test]
+    fn video_without_poster_keeps_fallback_content() {
+        assert_eq!(
+            simplify_str("<video><source src=\"v.mp4\"><a href=\"v.mp4\">download</a></video>"),
+            "<a href=\"v.mp4\">download</a>"
+        );
+    }
+
+    #[test]
+    fn sectioning_elements_become_divs() {
+        assert_eq!(
+            simplify_str("<nav><a href=\"/\">home</a></nav>"),
+            "<div><a href=\"/\">home</a></div>"
+        );
+    }
+
+    #[test]
+    fn small_styles_are_kept() {
+        assert_eq!(
+            simplify_str("<style>b { color: red }</style><b style=\"color: red\">"),
+            "<style>b { color: red }</style><b style=\"color: red\">"
+        );
+    }
+
+    #[test]
+    fn large_styles_are_dropped() {
+        assert_eq!(
+            simplify_str(
+                "<style>body { font-family: sans-serif }</style><b style=\"font-family: sans-serif\">"
+            ),
+            "<style></style><b>"
+        );
+    }
+
+    #[test]
+    fn http_links_survive() {
+        assert_eq!(
+            simplify_str("<a href=\"http://example.com/\">x</a>"),
+            "<a href=\"http://example.com/\">x</a>"
+        );
+    }
+}
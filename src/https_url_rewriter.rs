@@ -10,10 +10,15 @@ enum State {
     HaveHTTPS,
     HaveHTTPSC,  // c for colon innit
     HaveHTTPSCS, //s for slash innit
+    HaveW,
+    HaveWS,
+    HaveWSS,
+    HaveWSSC,
+    HaveWSSCS,
 }
 
-// rewrites any https:// into http:// in received chunks - even where it
-// spreads across chunk boundaries
+// rewrites any https:// into http://, and wss:// into ws://, in received
+// chunks - even where it spreads across chunk boundaries
 pub struct HttpsUrlRewriter {
     output_buffer: BytesMut,
     buffer: BytesMut,
@@ -40,6 +45,13 @@ impl HttpsUrlRewriter {
         bytes.freeze()
     }
 
+    // the output so far, plus whatever was held back in case it turned out
+    // to be the start of a scheme
+    pub fn finish(mut self) -> Bytes {
+        self.flush();
+        self.move_output()
+    }
+
     pub fn consume(&mut self, chr: u8) {
         match (self.state, chr) {
             (_, b'h') => {
@@ -52,7 +64,16 @@ impl HttpsUrlRewriter {
             (State::HaveHTTP, b's') => self.store(chr, State::HaveHTTPS),
             (State::HaveHTTPS, b':') => self.store(chr, State::HaveHTTPSC),
             (State::HaveHTTPSC, b'/') => self.store(chr, State::HaveHTTPSCS),
-            (State::HaveHTTPSCS, b'/') => self.output_http(),
+            (State::HaveHTTPSCS, b'/') => self.output(b"http://"),
+            (_, b'w') => {
+                self.flush();
+                self.store(chr, State::HaveW)
+            }
+            (State::HaveW, b's') => self.store(chr, State::HaveWS),
+            (State::HaveWS, b's') => self.store(chr, State::HaveWSS),
+            (State::HaveWSS, b':') => self.store(chr, State::HaveWSSC),
+            (State::HaveWSSC, b'/') => self.store(chr, State::HaveWSSCS),
+            (State::HaveWSSCS, b'/') => self.output(b"ws://"),
             _ => self.flush_and_output(chr),
         }
     }
@@ -79,9 +100,9 @@ impl HttpsUrlRewriter {
         self.state = next_state;
     }
 
-    // adds http:// to the output buffer and resets buffer & state
-    fn output_http(&mut self) {
-        self.output_buffer.put(&b"http://"[..]);
+    // adds the insecure scheme to the output buffer and resets buffer & state
+    fn output(&mut self, scheme: &[u8]) {
+        self.output_buffer.put(scheme);
         self.reset_buffer();
     }
 }
@@ -343,6 +364,38 @@ mod tests {
                 b"hello http://google.com/goog http://website http://example.com"
             );
         }
+
+        #[test]
+        fn finishing_keeps_a_trailing_partial_scheme() {
+            let mut rewriter = url_rewriter();
+
+            rewriter.consume_str(&mut Bytes::from_static(b"fetch it over http"));
+
+            assert_eq!(&rewriter.finish()[..], b"fetch it over http");
+        }
+
+        #[test]
+        fn rewrites_websocket_urls() {
+            let mut rewriter = url_rewriter();
+
+            rewriter.consume_str(&mut Bytes::from_static(b"new WebSocket('wss:"));
+            rewriter.consume_str(&mut Bytes::from_static(b"//example.com/chat') ws://x"));
+            rewriter.consume_str(&mut Bytes::from_static(b" wsws://y wwss://z"));
+
+            assert_eq!(
+                &rewriter.move_output()[..],
+                b"new WebSocket('ws://example.com/chat') ws://x wsws://y wws://z"
+            );
+        }
+
+        #[test]
+        fn finishing_keeps_a_trailing_partial_websocket_scheme() {
+            let mut rewriter = url_rewriter();
+
+            rewriter.consume_str(&mut Bytes::from_static(b"goodbye now"));
+
+            assert_eq!(&rewriter.finish()[..], b"goodbye now");
+        }
     }
 
     #[cfg(test)]
@@ -0,0 +1,255 @@
+use crate::content_type;
+
+use bytes::Bytes;
+use image::codecs::gif::GifEncoder;
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, Frame, ImageFormat, Rgb, RgbImage};
+use std::error::Error;
+use std::str::FromStr;
+
+// image types that legacy browsers can't display (or display badly) and that
+// we'll try to turn into something they can
+pub const TRANSCODED_IMAGE_MIMES: &[(&str, ImageFormat)] = &[
+    ("image/webp", ImageFormat::WebP),
+    #[cfg(feature = "avif")]
+    ("image/avif", ImageFormat::Avif),
+    ("image/png", ImageFormat::Png),
+];
+
+pub const DEFAULT_JPEG_QUALITY: u8 = 75;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TargetFormat {
+    Jpeg,
+    Gif,
+}
+
+impl TargetFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            TargetFormat::Jpeg => "image/jpeg",
+            TargetFormat::Gif => "image/gif",
+        }
+    }
+}
+
+impl FromStr for TargetFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "jpeg" | "jpg" => Ok(TargetFormat::Jpeg),
+            "gif" => Ok(TargetFormat::Gif),
+            other => Err(format!("unknown image format {:?}", other)),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ImageTranscoding {
+    pub target: TargetFormat,
+    // images wider or taller than this get scaled down to fit
+    pub max_dimension: Option<u32>,
+    pub jpeg_quality: u8,
+}
+
+pub struct TranscodedImage {
+    pub body: Bytes,
+    pub content_type: &'static str,
+}
+
+pub fn source_format(content_type: &str) -> Option<ImageFormat> {
+    TRANSCODED_IMAGE_MIMES
+        .iter()
+        .find(|(mime, _)| content_type::is(content_type, mime))
+        .map(|(_, format)| *format)
+}
+
+// Decodes the image and re-encodes it in the target format. Returns None when
+// the image is best left alone - either we weren't built with a decoder for
+// it, or it's a PNG which legacy browsers can already display properly.
+pub fn transcode(
+    body: &[u8],
+    content_type: &str,
+    options: &ImageTranscoding,
+) -> Result<Option<TranscodedImage>, Box<dyn Error>> {
+    let format = match source_format(content_type) {
+        Some(format) if format.reading_enabled() => format,
+        _ => return Ok(None),
+    };
+
+    let mut image = image::load_from_memory_with_format(body, format)?;
+    if format == ImageFormat::Png && !is_modern_png(&image) {
+        return Ok(None);
+    }
+
+    if let Some(max) = options.max_dimension {
+        if image.width() > max || image.height() > max {
+            image = image.resize(max, max, FilterType::Triangle);
+        }
+    }
+
+    let mut output = Vec::new();
+    match options.target {
+        TargetFormat::Jpeg => {
+            let encoder = JpegEncoder::new_with_quality(&mut output, options.jpeg_quality);
+            flatten_onto_white(&image).write_with_encoder(encoder)?;
+        }
+        TargetFormat::Gif => {
+            let mut encoder = GifEncoder::new_with_speed(&mut output, 10);
+            encoder.encode_frame(Frame::new(image.to_rgba8()))?;
+        }
+    }
+
+    Ok(Some(TranscodedImage {
+        body: Bytes::from(output),
+        content_type: options.target.content_type(),
+    }))
+}
+
+// plain 8-bit opaque PNGs work fine in old browsers. it's the alpha channel
+// (grey boxes in IE5) and 16-bit channels that cause trouble.
+fn is_modern_png(image: &DynamicImage) -> bool {
+    let color = image.color();
+    color.has_alpha() || color.bytes_per_pixel() > color.channel_count()
+}
+
+// JPEG has no transparency, so composite onto a white page-coloured background
+fn flatten_onto_white(image: &DynamicImage) -> RgbImage {
+    let rgba = image.to_rgba8();
+    RgbImage::from_fn(rgba.width(), rgba.height(), |x, y| {
+        let [r, g, b, a] = rgba.get_pixel(x, y).0;
+        let blend = |c: u8| ((c as u16 * a as u16 + 255 * (255 - a as u16)) / 255) as u8;
+        Rgb([blend(r), blend(g), blend(b)])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgba, RgbaImage};
+    use std::io::Cursor;
+
+    fn encode(image: DynamicImage, format: ImageFormat) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        image
+            .write_to(&mut Cursor::new(&mut bytes), format)
+            .unwrap();
+        bytes
+    }
+
+    fn transparent_png(width: u32, height: u32) -> Vec<u8> {
+        let image = RgbaImage::from_pixel(width, height, Rgba([255, 0, 0, 128]));
+        encode(DynamicImage::ImageRgba8(image), ImageFormat::Png)
+    }
+
+    fn options(target: TargetFormat) -> ImageTranscoding {
+        ImageTranscoding {
+            target,
+            max_dimension: None,
+            jpeg_quality: DEFAULT_JPEG_QUALITY,
+        }
+    }
+
+    #[test]
+    fn target_format_parses() {
+        assert_eq!("JPG".parse(), Ok(TargetFormat::Jpeg));
+        assert_eq!("gif".parse(), Ok(TargetFormat::Gif));
+        assert!("bmp".parse::<TargetFormat>().is_err());
+    }
+
+    #[test]
+    fn source_format_ignores_parameters() {
+        assert_eq!(source_format("image/webp; q=1"), Some(ImageFormat::WebP));
+        assert_eq!(source_format("image/jpeg"), None);
+    }
+
+    #[test]
+    fn transparent_png_becomes_jpeg() {
+        let png = transparent_png(4, 4);
+        let result = transcode(&png, "image/png", &options(TargetFormat::Jpeg))
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(result.content_type, "image/jpeg");
+        assert_eq!(
+            image::guess_format(&result.body).unwrap(),
+            ImageFormat::Jpeg
+        );
+    }
+
+    #[test]
+    fn transparent_png_becomes_gif() {
+        let png = transparent_png(4, 4);
+        let result = transcode(&png, "image/png", &options(TargetFormat::Gif))
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(result.content_type, "image/gif");
+        assert_eq!(image::guess_format(&result.body).unwrap(), ImageFormat::Gif);
+    }
+
+    #[cfg(feature = "avif")]
+    #[test]
+    fn avif_becomes_jpeg() {
+        // an 8x8 gradient
+        let avif = include_bytes!("../testdata/tiny.avif");
+        let result = transcode(avif, "image/avif", &options(TargetFormat::Jpeg))
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(result.content_type, "image/jpeg");
+        let jpeg = image::load_from_memory(&result.body).unwrap();
+        assert_eq!((jpeg.width(), jpeg.height()), (8, 8));
+    }
+
+    #[test]
+    fn opaque_png_is_left_alone() {
+        let image = RgbImage::from_pixel(4, 4, Rgb([0, 0, 255]));
+        let png = encode(DynamicImage::ImageRgb8(image), ImageFormat::Png);
+
+        let result = transcode(&png, "image/png", &options(TargetFormat::Jpeg)).unwrap();
+
+        assert!(result.is_none());
+    }
+
+    #[test]
+    fn webp_becomes_jpeg() {
+        let image = RgbaImage::from_pixel(8, 8, Rgba([0, 255, 0, 255]));
+        let webp = encode(DynamicImage::ImageRgba8(image), ImageFormat::WebP);
+
+        let result = transcode(&webp, "image/webp", &options(TargetFormat::Jpeg))
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(
+            image::guess_format(&result.body).unwrap(),
+            ImageFormat::Jpeg
+        );
+    }
+
+    #[test]
+    fn large_images_are_downscaled_keeping_aspect_ratio() {
+        let png = transparent_png(400, 200);
+        let mut opts = options(TargetFormat::Jpeg);
+        opts.max_dimension = Some(100);
+
+        let result = transcode(&png, "image/png", &opts).unwrap().unwrap();
+        let decoded = image::load_from_memory(&result.body).unwrap();
+
+        assert_eq!((decoded.width(), decoded.height()), (100, 50));
+    }
+
+    #[test]
+    fn garbage_is_an_error() {
+        assert!(transcode(b"not a png", "image/png", &options(TargetFormat::Jpeg)).is_err());
+    }
+
+    #[test]
+    fn flatten_onto_white_blends_alpha() {
+        let image = RgbaImage::from_pixel(1, 1, Rgba([0, 0, 0, 0]));
+        let flat = flatten_onto_white(&DynamicImage::ImageRgba8(image));
+        assert_eq!(flat.get_pixel(0, 0), &Rgb([255, 255, 255]));
+    }
+}
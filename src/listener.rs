@@ -0,0 +1,471 @@
+use crate::client_acl::{networks_from_env, ClientAcl, Rejection};
+use crate::config::{env_flag, env_list, env_name, env_parse, env_string};
+use crate::connection::Connection;
+use crate::original_dst::{self, OriginalDst};
+use crate::the_insecure_proxy::{the_insecure_proxy, TheInsecureProxy};
+use crate::tls_interception::TlsInterception;
+
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper_util::rt::TokioIo;
+use ipnet::IpNet;
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+
+pub const DEFAULT_LISTENER_NAME: &str = "default";
+pub const TLS_LISTENER_NAME: &str = "tls";
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ListenAddress {
+    Tcp(SocketAddr),
+    // "unix:/run/the-insecure-proxy.sock"
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddress {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        match s.strip_prefix("unix:") {
+            Some(path) if !path.is_empty() => Ok(ListenAddress::Unix(PathBuf::from(path))),
+            Some(_) => Err("unix: needs a socket path".to_string()),
+            None => s
+                .parse()
+                .map(ListenAddress::Tcp)
+                .map_err(|_| format!("{:?} is not an address:port or unix:path", s)),
+        }
+    }
+}
+
+impl fmt::Display for ListenAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ListenAddress::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddress::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+// What kind of requests a listener expects
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum ListenerMode {
+    // both of the below, as the proxy has always done
+    #[default]
+    Proxy,
+    // redirected traffic, routed by its Host header - no CONNECT
+    Transparent,
+    // browsers configured to use us as their HTTP proxy
+    Explicit,
+    // health checks and the like - nothing is proxied
+    Admin,
+}
+
+impl FromStr for ListenerMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "proxy" => Ok(ListenerMode::Proxy),
+            "transparent" => Ok(ListenerMode::Transparent),
+            "explicit" => Ok(ListenerMode::Explicit),
+            "admin" => Ok(ListenerMode::Admin),
+            other => Err(format!("unknown listener mode {:?}", other)),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Listener {
+    pub name: String,
+    pub address: ListenAddress,
+    pub mode: ListenerMode,
+    // the client profile everyone on this listener gets, instead of picking
+    // one by User-Agent or address
+    pub profile: Option<String>,
+    // terminate legacy TLS before reading HTTP
+    pub tls: bool,
+    pub original_dst: OriginalDst,
+    pub proxy_protocol: bool,
+    // the load balancers whose PROXY protocol headers we believe
+    pub proxy_protocol_from: Vec<IpNet>,
+    pub acl: ClientAcl,
+    // require Proxy-Authorization from clients
+    pub proxy_auth: bool,
+}
+
+impl Listener {
+    pub fn new(name: &str, address: ListenAddress) -> Listener {
+        Listener {
+            name: name.to_string(),
+            address,
+            mode: ListenerMode::default(),
+            profile: None,
+            tls: false,
+            original_dst: OriginalDst::default(),
+            proxy_protocol: false,
+            proxy_protocol_from: Vec::new(),
+            acl: ClientAcl::default(),
+            proxy_auth: false,
+        }
+    }
+
+    // Listeners are configured with variables prefixed LISTENER_{NAME}_, e.g.
+    // LISTENER_ADMIN_ADDRESS=127.0.0.1:3081
+    fn from_env(name: &str) -> Result<Listener, Box<dyn Error>> {
+        let prefix = format!("LISTENER_{}_", env_name(name));
+        let address_var = format!("{}ADDRESS", prefix);
+        let address = match env_string(&address_var)? {
+            Some(address) => address
+                .parse()
+                .map_err(|err| format!("{} was not valid: {}", address_var, err))?,
+            None => return Err(format!("listener {} needs {}", name, address_var).into()),
+        };
+
+        Listener {
+            mode: env_parse(&format!("{}MODE", prefix))?.unwrap_or_default(),
+            profile: env_string(&format!("{}PROFILE", prefix))?.filter(|p| !p.is_empty()),
+            tls: env_flag(&format!("{}TLS", prefix))?,
+            original_dst: env_parse(&format!("{}ORIGINAL_DST", prefix))?.unwrap_or_default(),
+            proxy_protocol: env_flag(&format!("{}PROXY_PROTOCOL", prefix))?,
+            proxy_protocol_from: networks_from_env(&format!("{}PROXY_PROTOCOL_FROM", prefix))?,
+            acl: ClientAcl::from_env(&prefix)?,
+            proxy_auth: env_flag(&format!("{}PROXY_AUTH", prefix))?,
+            ..Listener::new(name, address)
+        }
+        .checked(&prefix)
+    }
+
+    // a PROXY protocol header says who the client is, so only the balancers
+    // in front of us get to send one
+    fn checked(self, prefix: &str) -> Result<Listener, Box<dyn Error>> {
+        if self.proxy_protocol && self.proxy_protocol_from.is_empty() {
+            return Err(format!(
+                "{}PROXY_PROTOCOL needs {}PROXY_PROTOCOL_FROM to say who may send it",
+                prefix, prefix
+            )
+            .into());
+        }
+        Ok(self)
+    }
+
+    // Whether to read a PROXY protocol header from peer. Anyone else is
+    // taken to be the client, and whatever they send is read as HTTP.
+    pub fn reads_proxy_header(&self, peer: IpAddr) -> bool {
+        let peer = peer.to_canonical();
+        self.proxy_protocol
+            && self
+                .proxy_protocol_from
+                .iter()
+                .any(|net| net.contains(&peer))
+    }
+}
+
+// LISTENERS lists the listeners to bind. Without it there's the one on
+// BIND_ADDRESS and PORT, plus one for TLS interception if TLS_INTERCEPT_PORT
+// is set, configured by the unprefixed variables.
+pub fn from_env() -> Result<Vec<Listener>, Box<dyn Error>> {
+    let names = env_list("LISTENERS")?;
+    if !names.is_empty() {
+        return names.iter().map(|name| Listener::from_env(name)).collect();
+    }
+
+    let default_address = default_address()?;
+    let acl = ClientAcl::from_env("")?;
+    let mut listeners = vec![Listener {
+        original_dst: env_parse("ORIGINAL_DST")?.unwrap_or_default(),
+        proxy_protocol: env_flag("PROXY_PROTOCOL")?,
+        proxy_protocol_from: networks_from_env("PROXY_PROTOCOL_FROM")?,
+        acl: acl.clone(),
+        proxy_auth: env_flag("PROXY_AUTH")?,
+        ..Listener::new(DEFAULT_LISTENER_NAME, ListenAddress::Tcp(default_address))
+    }
+    .checked("")?];
+    if let Some(port) = env_parse::<u16>("TLS_INTERCEPT_PORT")? {
+        let address = SocketAddr::new(default_address.ip(), port);
+        listeners.push(
+            Listener {
+                tls: true,
+                original_dst: env_parse("TLS_INTERCEPT_ORIGINAL_DST")?.unwrap_or_default(),
+                proxy_protocol: env_flag("TLS_INTERCEPT_PROXY_PROTOCOL")?,
+                proxy_protocol_from: networks_from_env("TLS_INTERCEPT_PROXY_PROTOCOL_FROM")?,
+                acl,
+                ..Listener::new(TLS_LISTENER_NAME, ListenAddress::Tcp(address))
+            }
+            .checked("TLS_INTERCEPT_")?,
+        );
+    }
+    Ok(listeners)
+}
+
+// BIND_ADDRESS and PORT, for when LISTENERS isn't set
+fn default_address() -> Result<SocketAddr, Box<dyn Error>> {
+    let default_address = "0.0.0.0";
+    let default_port = 3080;
+
+    let address = match env::var("BIND_ADDRESS") {
+        Ok(addr) => Ok(addr),
+        Err(env::VarError::NotPresent) => Ok(String::from(default_address)),
+        Err(_) => Err("BIND_ADDRESS was not valid"),
+    }?;
+
+    let port: u16 = match env::var("PORT") {
+        Ok(port) => str::parse(&port).or(Err("PORT was not a valid integer")),
+        Err(env::VarError::NotPresent) => Ok(default_port),
+        Err(env::VarError::NotUnicode(_)) => Err("PORT was not valid"),
+    }?;
+
+    let ip_addr: IpAddr = address.parse()?;
+
+    Ok(SocketAddr::from((ip_addr, port)))
+}
+
+// Binds the listener and serves connections on it until the task is dropped
+pub async fn run(
+    listener: Listener,
+    interception: Option<Arc<TlsInterception>>,
+    proxy: Arc<TheInsecureProxy>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let listener = Arc::new(listener);
+    let interception = match (listener.tls, interception) {
+        (true, Some(interception)) => Some(interception),
+        (true, None) => return Err("TLS interception isn't configured".into()),
+        (false, _) => None,
+    };
+
+    match &listener.address {
+        ListenAddress::Tcp(addr) => {
+            let socket = bind_tcp(*addr)?;
+            println!("Listening on {} ({})", addr, listener.name);
+            loop {
+                let (stream, peer_addr) = match socket.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        eprintln!("accept error: {}", e);
+                        continue;
+                    }
+                };
+                let original_dst = match listener.original_dst {
+                    OriginalDst::Ignore => None,
+                    _ => original_dst::lookup(&stream),
+                };
+                accept(
+                    stream,
+                    peer_addr,
+                    original_dst,
+                    listener.clone(),
+                    interception.clone(),
+                    proxy.clone(),
+                );
+            }
+        }
+        #[cfg(unix)]
+        ListenAddress::Unix(path) => {
+            // a socket left over from last time would stop us binding
+            use std::os::unix::fs::FileTypeExt;
+            if std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+                std::fs::remove_file(path)?;
+            }
+            let socket = tokio::net::UnixListener::bind(path)?;
+            println!("Listening on {} ({})", listener.address, listener.name);
+            // local clients count as localhost, unless a PROXY protocol
+            // header says otherwise
+            let peer_addr = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+            loop {
+                let stream = match socket.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        eprintln!("accept error: {}", e);
+                        continue;
+                    }
+                };
+                accept(
+                    stream,
+                    peer_addr,
+                    None,
+                    listener.clone(),
+                    interception.clone(),
+                    proxy.clone(),
+                );
+            }
+        }
+        #[cfg(not(unix))]
+        ListenAddress::Unix(_) => Err("Unix sockets aren't supported on this platform".into()),
+    }
+}
+
+// Like TcpListener::bind, except IPv6 listeners only take IPv6 - otherwise
+// [::]:3080 would clash with a listener on 0.0.0.0:3080
+fn bind_tcp(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    let socket = socket2::Socket::new(
+        socket2::Domain::for_address(addr),
+        socket2::Type::STREAM,
+        Some(socket2::Protocol::TCP),
+    )?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    TcpListener::from_std(socket.into())
+}
+
+// Reading a PROXY protocol header or doing a TLS handshake can take a while,
+// so both happen on the connection's own task rather than holding up the
+// accept loop.
+fn accept<S>(
+    mut stream: S,
+    peer_addr: SocketAddr,
+    original_dst: Option<SocketAddr>,
+    listener: Arc<Listener>,
+    interception: Option<Arc<TlsInterception>>,
+    proxy: Arc<TheInsecureProxy>,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    tokio::task::spawn(async move {
+        let connection =
+            match Connection::accept(&mut stream, peer_addr, original_dst, listener).await {
+                Ok(connection) => connection,
+                Err(err) => {
+                    println!("= Dropping connection from {}: {}", peer_addr, err);
+                    return;
+                }
+            };
+
+        let acl = &connection.listener.acl;
+        if acl.rejection == Rejection::Close && !acl.allows(connection.client_addr.ip()) {
+            println!(
+                "= Refusing connection from {} on {}",
+                connection.client_addr, connection.listener.name
+            );
+            proxy.stats.record_denied_client(&connection.listener.name);
+            return;
+        }
+
+        match interception {
+            Some(interception) => match interception.accept(stream).await {
+                Ok(stream) => serve(stream, connection, proxy).await,
+                Err(err) => println!(
+                    "= TLS handshake with {} failed: {}",
+                    connection.client_addr, err
+                ),
+            },
+            None => serve(stream, connection, proxy).await,
+        }
+    });
+}
+
+async fn serve<S>(stream: S, connection: Connection, proxy: Arc<TheInsecureProxy>)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let io = TokioIo::new(stream);
+    if let Err(err) = http1::Builder::new()
+        .serve_connection(
+            io,
+            service_fn(move |req| the_insecure_proxy(proxy.clone(), connection.clone(), req)),
+        )
+        .with_upgrades()
+        .await
+    {
+        eprintln!("Error serving connection: {:?}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_tcp_addresses() {
+        assert_eq!(
+            "0.0.0.0:3080".parse(),
+            Ok(ListenAddress::Tcp("0.0.0.0:3080".parse().unwrap()))
+        );
+        assert_eq!(
+            "[::]:3080".parse(),
+            Ok(ListenAddress::Tcp("[::]:3080".parse().unwrap()))
+        );
+        assert!("0.0.0.0".parse::<ListenAddress>().is_err());
+    }
+
+    #[test]
+    fn parse_unix_addresses() {
+        assert_eq!(
+            "unix:/run/proxy.sock".parse(),
+            Ok(ListenAddress::Unix(PathBuf::from("/run/proxy.sock")))
+        );
+        assert!("unix:".parse::<ListenAddress>().is_err());
+    }
+
+    #[test]
+    fn display_round_trips() {
+        for address in ["127.0.0.1:3080", "[::1]:3080", "unix:/tmp/proxy.sock"] {
+            assert_eq!(
+                address.parse::<ListenAddress>().unwrap().to_string(),
+                address
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn ipv4_and_ipv6_listeners_share_a_port() {
+        let v4 = bind_tcp("127.0.0.1:0".parse().unwrap()).unwrap();
+        let port = v4.local_addr().unwrap().port();
+        // not every machine has IPv6, but where it does the port mustn't be
+        // taken already
+        if let Err(err) = bind_tcp(SocketAddr::new("::".parse().unwrap(), port)) {
+            assert_ne!(err.kind(), std::io::ErrorKind::AddrInUse);
+        }
+    }
+
+    #[test]
+    fn only_trusted_peers_send_proxy_headers() {
+        let listener = Listener {
+            proxy_protocol: true,
+            proxy_protocol_from: vec!["10.0.0.0/8".parse().unwrap()],
+            ..Listener::new("test", "127.0.0.1:0".parse().unwrap())
+        };
+        assert!(listener.reads_proxy_header("10.1.2.3".parse().unwrap()));
+        assert!(listener.reads_proxy_header("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!listener.reads_proxy_header("192.0.2.1".parse().unwrap()));
+
+        let listener = Listener {
+            proxy_protocol: false,
+            ..listener
+        };
+        assert!(!listener.reads_proxy_header("10.1.2.3".parse().unwrap()));
+    }
+
+    #[test]
+    fn proxy_protocol_needs_trusted_peers() {
+        let listener = Listener {
+            proxy_protocol: true,
+            ..Listener::new("test", "127.0.0.1:0".parse().unwrap())
+        };
+        assert!(listener.clone().checked("").is_err());
+        let listener = Listener {
+            proxy_protocol_from: vec!["127.0.0.1/32".parse().unwrap()],
+            ..listener
+        };
+        assert!(listener.checked("").is_ok());
+    }
+
+    #[test]
+    fn parse_modes() {
+        assert_eq!("Explicit".parse(), Ok(ListenerMode::Explicit));
+        assert_eq!("admin".parse(), Ok(ListenerMode::Admin));
+        assert!("sideways".parse::<ListenerMode>().is_err());
+    }
+}
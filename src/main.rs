@@ -1,49 +1,48 @@
+mod admin;
+mod byte_range;
+mod charset_transcoder;
+mod client_acl;
+mod client_profile;
+mod config;
+mod connect_tunnel;
+mod connection;
+mod content_type;
+mod destination_policy;
+mod disk_cache;
+mod dns_resolver;
+mod error_page;
+mod forwarded;
+mod hop_by_hop;
+mod host_pattern;
+mod html;
+mod html_simplifier;
 mod https_url_rewriter;
+mod image_transcoder;
+mod listener;
+mod original_dst;
+mod proxy_auth;
 mod proxy_error;
+mod proxy_protocol;
+mod response_cache;
+mod srcset;
+mod stats;
 mod the_insecure_proxy;
+#[cfg(feature = "tls-interception")]
+mod tls_interception;
+#[cfg(not(feature = "tls-interception"))]
+#[path = "no_tls_interception.rs"]
+mod tls_interception;
+mod upstream_proxy;
+mod upstream_tls;
+mod websocket;
 
-use the_insecure_proxy::the_insecure_proxy;
+use listener::ListenAddress;
+use the_insecure_proxy::TheInsecureProxy;
+use tls_interception::TlsInterception;
 
-use hyper::server::conn::http1;
-use hyper::service::service_fn;
-use hyper_util::rt::TokioIo;
-use std::{env, net};
-use tokio::net::TcpListener;
-
-
-fn listen_addr() -> Result<net::SocketAddr, Box<dyn std::error::Error>> {
-    let default_address = "0.0.0.0";
-    let default_port = 3080;
-
-    let address = match env::var("BIND_ADDRESS") {
-        Ok(addr) => Ok(addr),
-        Err(env::VarError::NotPresent) => Ok(String::from(default_address)),
-        Err(_) => Err("BIND_ADDRESS was not valid"),
-    }?;
-
-    let port: u16 = match env::var("PORT") {
-        Ok(port) => str::parse(&port).or(Err("PORT was not a valid integer")),
-        Err(env::VarError::NotPresent) => Ok(default_port),
-        Err(env::VarError::NotUnicode(_)) => Err("PORT was not valid"),
-    }?;
-
-    let ip_addr: net::IpAddr = address.parse()?;
-
-    Ok(net::SocketAddr::from((ip_addr, port)))
-}
-
-async fn accept_connection(stream: tokio::net::TcpStream) {
-    let io = TokioIo::new(stream);
-
-    tokio::task::spawn(async move {
-        if let Err(err) = http1::Builder::new()
-            .serve_connection(io, service_fn(the_insecure_proxy))
-            .await
-        {
-            eprintln!("Error serving connection: {:?}", err);
-        }
-    });
-}
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::task::JoinSet;
 
 async fn graceful_shutdown() {
     tokio::signal::ctrl_c()
@@ -54,37 +53,87 @@ async fn graceful_shutdown() {
 
 #[tokio::main]
 async fn main() {
-    let addr = listen_addr();
-    let addr = match addr {
-        Ok(addr) => {
-            println!("Booting server on {}", addr);
-            addr
+    let proxy = match TheInsecureProxy::from_env() {
+        Ok(proxy) => Arc::new(proxy),
+        Err(x) => {
+            println!("{}", x);
+            std::process::exit(1);
+        }
+    };
+
+    let listeners = match listener::from_env() {
+        Ok(listeners) => listeners,
+        Err(x) => {
+            println!("{}", x);
+            std::process::exit(1);
         }
+    };
+    for listener in &listeners {
+        if let Some(profile) = &listener.profile {
+            if !proxy.has_profile(profile) {
+                println!(
+                    "Listener {} uses unknown profile {}",
+                    listener.name, profile
+                );
+                std::process::exit(1);
+            }
+        }
+    }
+
+    if !proxy.has_proxy_auth() && listeners.iter().any(|listener| listener.proxy_auth) {
+        println!("Proxy authentication needs PROXY_AUTH_FILE");
+        std::process::exit(1);
+    }
+
+    let interception = match TlsInterception::from_env() {
+        Ok(interception) => interception.map(Arc::new),
         Err(x) => {
             println!("{}", x);
             std::process::exit(1);
         }
     };
+    if interception.is_none() && listeners.iter().any(|listener| listener.tls) {
+        println!("TLS listeners need TLS_INTERCEPT_CA_CERT and TLS_INTERCEPT_CA_KEY");
+        std::process::exit(1);
+    }
 
-    let listener = TcpListener::bind(addr).await.unwrap();
+    let socket_paths: Vec<PathBuf> = listeners
+        .iter()
+        .filter_map(|listener| match &listener.address {
+            ListenAddress::Unix(path) => Some(path.clone()),
+            ListenAddress::Tcp(_) => None,
+        })
+        .collect();
+
+    let mut tasks = JoinSet::new();
+    for listener in listeners {
+        let interception = interception.clone();
+        let proxy = proxy.clone();
+        tasks.spawn(async move {
+            let name = listener.name.clone();
+            (name, listener::run(listener, interception, proxy).await)
+        });
+    }
     println!("Now listening!");
 
-    loop {
-        tokio::select! {
-            result = listener.accept() => {
-                match result {
-                    Ok((stream, _)) => {
-                        accept_connection(stream).await;
-                    }
-                    Err(e) => {
-                        eprintln!("accept error: {}", e);
-                    }
-                }
-            }
-            _ = graceful_shutdown() => {
-                break;
+    // listeners only stop by failing, so any of them finishing is fatal
+    tokio::select! {
+        finished = tasks.join_next() => {
+            match finished {
+                Some(Ok((name, Err(err)))) => println!("Listener {} failed: {}", name, err),
+                Some(Ok((name, Ok(())))) => println!("Listener {} stopped", name),
+                Some(Err(err)) => println!("A listener crashed: {}", err),
+                None => println!("There are no listeners"),
             }
+            std::process::exit(1);
         }
+        _ = graceful_shutdown() => {}
+    }
+
+    // dropping the listeners closes them all
+    tasks.shutdown().await;
+    for path in socket_paths {
+        let _ = std::fs::remove_file(path);
     }
 
     println!("Server stopped.");
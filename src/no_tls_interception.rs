@@ -0,0 +1,28 @@
+use crate::config::env_string;
+
+use std::error::Error;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+// Stands in for TLS interception in builds without the tls-interception
+// feature, which needs OpenSSL for the ciphers legacy clients speak. Nothing
+// can make one, so TLS listeners are refused at startup.
+pub enum TlsInterception {}
+
+impl TlsInterception {
+    pub fn from_env() -> Result<Option<TlsInterception>, Box<dyn Error>> {
+        match (
+            env_string("TLS_INTERCEPT_CA_CERT")?,
+            env_string("TLS_INTERCEPT_CA_KEY")?,
+        ) {
+            (None, None) => Ok(None),
+            _ => Err("this build doesn't include TLS interception".into()),
+        }
+    }
+
+    pub async fn accept<S>(&self, _stream: S) -> Result<S, Box<dyn Error + Send + Sync>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        match *self {}
+    }
+}
@@ -0,0 +1,259 @@
+use crate::dns_resolver::DnsResolver;
+
+use hyper::header::HOST;
+use hyper::http::HeaderValue;
+use hyper::Request;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use tokio::net::TcpStream;
+
+// What to do with the address a client was really trying to reach, when an
+// iptables DNAT/REDIRECT rule sent it to us instead
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum OriginalDst {
+    // route on the Host header alone
+    #[default]
+    Ignore,
+    // use the original destination for requests with no Host header
+    Fallback,
+    // as Fallback, and refuse requests whose Host doesn't resolve to the
+    // original destination
+    Verify,
+}
+
+impl FromStr for OriginalDst {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "" | "off" | "ignore" => Ok(OriginalDst::Ignore),
+            "fallback" => Ok(OriginalDst::Fallback),
+            "verify" => Ok(OriginalDst::Verify),
+            other => Err(format!("unknown original destination mode {:?}", other)),
+        }
+    }
+}
+
+// The address the connection was headed for before it was redirected to us,
+// or None if it wasn't redirected (or we can't tell on this platform)
+pub fn lookup(stream: &TcpStream) -> Option<SocketAddr> {
+    let original = original_dst(stream)?;
+    match stream.local_addr() {
+        Ok(local) if local == original => None,
+        _ => Some(original),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn original_dst(stream: &TcpStream) -> Option<SocketAddr> {
+    let socket = socket2::SockRef::from(stream);
+    let original = match stream.local_addr().ok()? {
+        SocketAddr::V4(_) => socket.original_dst_v4(),
+        SocketAddr::V6(_) => socket.original_dst_v6(),
+    };
+    original.ok()?.as_socket()
+}
+
+#[cfg(not(target_os = "linux"))]
+fn original_dst(_stream: &TcpStream) -> Option<SocketAddr> {
+    None
+}
+
+// Fills in a missing Host header from the original destination and, in
+// Verify mode, checks that the Host given really is where the client was
+// going, looking it up the same way the request will be sent. Errors are
+// reasons to refuse the request.
+pub async fn apply<B>(
+    mode: OriginalDst,
+    original: Option<SocketAddr>,
+    resolver: &DnsResolver,
+    mut req: Request<B>,
+) -> Result<Request<B>, String> {
+    let original = match (mode, original) {
+        (OriginalDst::Ignore, _) | (_, None) => return Ok(req),
+        (_, Some(original)) => original,
+    };
+
+    let host = req
+        .headers()
+        .get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .filter(|host| !host.is_empty())
+        .map(str::to_string);
+    let host = match host {
+        Some(host) => host,
+        None => {
+            let host = host_for(original);
+            println!("= No Host header, using original destination {}", host);
+            req.headers_mut().insert(
+                HOST,
+                HeaderValue::from_str(&host).map_err(|err| err.to_string())?,
+            );
+            return Ok(req);
+        }
+    };
+
+    if mode == OriginalDst::Verify && !resolves_to(resolver, &host, original).await {
+        return Err(format!("{} is not {}", host, original));
+    }
+    Ok(req)
+}
+
+// the standard ports are left off - upstream requests go over HTTPS whichever
+// of them the client was using
+fn host_for(addr: SocketAddr) -> String {
+    let ip = match addr {
+        SocketAddr::V4(addr) => addr.ip().to_string(),
+        SocketAddr::V6(addr) => format!("[{}]", addr.ip()),
+    };
+    match addr.port() {
+        80 | 443 => ip,
+        port => format!("{}:{}", ip, port),
+    }
+}
+
+async fn resolves_to(resolver: &DnsResolver, host: &str, original: SocketAddr) -> bool {
+    let name = match host.rsplit_once(':') {
+        Some((name, port)) if port.parse::<u16>().is_ok() && !name.ends_with(':') => name,
+        _ => host,
+    };
+    match resolver.resolve(name).await {
+        Ok(resolved) => resolved
+            .addrs
+            .iter()
+            .any(|addr| addr.to_canonical() == original.ip().to_canonical()),
+        Err(err) => {
+            println!("= Couldn't resolve {}: {}", name, err);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns_resolver::{IpPreference, DEFAULT_CACHE_TTL};
+
+    fn request(host: Option<&str>) -> Request<()> {
+        let mut builder = Request::builder().uri("/");
+        if let Some(host) = host {
+            builder = builder.header(HOST, host);
+        }
+        builder.body(()).unwrap()
+    }
+
+    fn host_of(req: &Request<()>) -> Option<&str> {
+        req.headers().get(HOST).map(|host| host.to_str().unwrap())
+    }
+
+    #[test]
+    fn parse_modes() {
+        assert_eq!("fallback".parse(), Ok(OriginalDst::Fallback));
+        assert_eq!("VERIFY".parse(), Ok(OriginalDst::Verify));
+        assert_eq!("off".parse(), Ok(OriginalDst::Ignore));
+        assert!("sometimes".parse::<OriginalDst>().is_err());
+    }
+
+    #[test]
+    fn host_for_leaves_off_standard_ports() {
+        assert_eq!(host_for("192.0.2.1:80".parse().unwrap()), "192.0.2.1");
+        assert_eq!(
+            host_for("192.0.2.1:8080".parse().unwrap()),
+            "192.0.2.1:8080"
+        );
+        assert_eq!(
+            host_for("[2001:db8::1]:443".parse().unwrap()),
+            "[2001:db8::1]"
+        );
+    }
+
+    #[tokio::test]
+    async fn ignore_leaves_request_alone() {
+        let req = apply(
+            OriginalDst::Ignore,
+            "192.0.2.1:80".parse().ok(),
+            &DnsResolver::default(),
+            request(None),
+        )
+        .await
+        .unwrap();
+        assert_eq!(host_of(&req), None);
+    }
+
+    #[tokio::test]
+    async fn fallback_fills_in_missing_host() {
+        let req = apply(
+            OriginalDst::Fallback,
+            "192.0.2.1:80".parse().ok(),
+            &DnsResolver::default(),
+            request(None),
+        )
+        .await
+        .unwrap();
+        assert_eq!(host_of(&req), Some("192.0.2.1"));
+    }
+
+    #[tokio::test]
+    async fn fallback_trusts_given_host() {
+        let req = apply(
+            OriginalDst::Fallback,
+            "192.0.2.1:80".parse().ok(),
+            &DnsResolver::default(),
+            request(Some("example.com")),
+        )
+        .await
+        .unwrap();
+        assert_eq!(host_of(&req), Some("example.com"));
+    }
+
+    #[tokio::test]
+    async fn verify_accepts_matching_host() {
+        let req = apply(
+            OriginalDst::Verify,
+            "127.0.0.1:80".parse().ok(),
+            &DnsResolver::default(),
+            request(Some("127.0.0.1:80")),
+        )
+        .await;
+        assert!(req.is_ok());
+    }
+
+    #[tokio::test]
+    async fn verify_refuses_mismatched_host() {
+        let req = apply(
+            OriginalDst::Verify,
+            "192.0.2.1:80".parse().ok(),
+            &DnsResolver::default(),
+            request(Some("127.0.0.1")),
+        )
+        .await;
+        assert!(req.is_err());
+    }
+
+    #[tokio::test]
+    async fn verify_resolves_like_the_proxy_does() {
+        let resolver = DnsResolver::new(
+            vec![("mirror.lab".parse().unwrap(), "192.0.2.7".parse().unwrap())],
+            vec![],
+            IpPreference::Any,
+            DEFAULT_CACHE_TTL,
+        );
+        let req = apply(
+            OriginalDst::Verify,
+            "192.0.2.7:80".parse().ok(),
+            &resolver,
+            request(Some("mirror.lab")),
+        )
+        .await;
+        assert!(req.is_ok());
+    }
+
+    #[tokio::test]
+    async fn lookup_without_redirect_is_none() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let _client = TcpStream::connect(addr).await.unwrap();
+        let (stream, _) = listener.accept().await.unwrap();
+        assert_eq!(lookup(&stream), None);
+    }
+}
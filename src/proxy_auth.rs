@@ -0,0 +1,225 @@
+use crate::config::env_string;
+
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Mutex;
+
+pub const DEFAULT_REALM: &str = "The Insecure Proxy";
+
+// Browsers send credentials with every request and checking a bcrypt hash
+// takes a good fraction of a second, so headers we've already accepted are
+// remembered - up to this many of them.
+const MAX_REMEMBERED: usize = 1024;
+
+// checked instead when the user doesn't exist, so that a wrong user name
+// takes as long to refuse as a wrong password and names can't be guessed by
+// timing. It's a hash of nothing anyone could be sending.
+const DUMMY_HASH: &str = "$2b$10$Gei0rvY6jlLwDmQkkGEbI.dHbHSfY59gnhwgx762nKoclUyUIRldC";
+
+#[derive(Debug, PartialEq, Clone)]
+enum Hash {
+    Bcrypt(String),
+    // any PHC-format hash argon2 understands
+    Argon2(String),
+}
+
+impl Hash {
+    fn parse(hash: &str) -> Result<Hash, String> {
+        if ["$2a$", "$2b$", "$2x$", "$2y$"]
+            .iter()
+            .any(|prefix| hash.starts_with(prefix))
+        {
+            Ok(Hash::Bcrypt(hash.to_string()))
+        } else if hash.starts_with("$argon2") {
+            PasswordHash::new(hash).map_err(|err| err.to_string())?;
+            Ok(Hash::Argon2(hash.to_string()))
+        } else {
+            Err("only bcrypt and argon2 hashes are supported".to_string())
+        }
+    }
+
+    fn verify(&self, password: &str) -> bool {
+        match self {
+            Hash::Bcrypt(hash) => bcrypt::verify(password, hash).unwrap_or(false),
+            Hash::Argon2(hash) => PasswordHash::new(hash).is_ok_and(|hash| {
+                Argon2::default()
+                    .verify_password(password.as_bytes(), &hash)
+                    .is_ok()
+            }),
+        }
+    }
+}
+
+// Checks Proxy-Authorization: Basic credentials against a file of
+// "user:hash" lines, as made by `htpasswd -B`
+pub struct ProxyAuth {
+    users: HashMap<String, Hash>,
+    pub realm: String,
+    // Proxy-Authorization header -> user
+    remembered: Mutex<HashMap<String, String>>,
+}
+
+impl ProxyAuth {
+    pub fn parse(credentials: &str, realm: &str) -> Result<ProxyAuth, String> {
+        let mut users = HashMap::new();
+        for (number, line) in credentials.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (user, hash) = line
+                .split_once(':')
+                .ok_or_else(|| format!("line {} isn't user:hash", number + 1))?;
+            let hash = Hash::parse(hash).map_err(|err| format!("line {}: {}", number + 1, err))?;
+            users.insert(user.to_string(), hash);
+        }
+
+        Ok(ProxyAuth {
+            users,
+            realm: realm.to_string(),
+            remembered: Mutex::new(HashMap::new()),
+        })
+    }
+
+    // None unless PROXY_AUTH_FILE is set
+    pub fn from_env() -> Result<Option<ProxyAuth>, Box<dyn Error>> {
+        let path = match env_string("PROXY_AUTH_FILE")? {
+            Some(path) if !path.is_empty() => path,
+            _ => return Ok(None),
+        };
+        let realm = env_string("PROXY_AUTH_REALM")?.unwrap_or_else(|| DEFAULT_REALM.to_string());
+        let credentials = std::fs::read_to_string(&path)
+            .map_err(|err| format!("couldn't read PROXY_AUTH_FILE {}: {}", path, err))?;
+        ProxyAuth::parse(&credentials, &realm)
+            .map(Some)
+            .map_err(|err| format!("PROXY_AUTH_FILE {} was not valid: {}", path, err).into())
+    }
+
+    // Returns the user a Proxy-Authorization header belongs to, if it's
+    // right. Hashes are checked on the blocking pool, as they're slow on
+    // purpose.
+    pub async fn authenticate(&self, header: Option<&str>) -> Option<String> {
+        let header = header?;
+        if let Some(user) = self.remembered.lock().unwrap().get(header) {
+            return Some(user.clone());
+        }
+
+        let (user, password) = decode_basic(header)?;
+        let (hash, known) = match self.users.get(&user) {
+            Some(hash) => (hash.clone(), true),
+            None => (Hash::Bcrypt(DUMMY_HASH.to_string()), false),
+        };
+        let verified = tokio::task::spawn_blocking(move || hash.verify(&password))
+            .await
+            .unwrap_or(false);
+        if !verified || !known {
+            return None;
+        }
+
+        let mut remembered = self.remembered.lock().unwrap();
+        if remembered.len() >= MAX_REMEMBERED {
+            remembered.clear();
+        }
+        remembered.insert(header.to_string(), user.clone());
+        Some(user)
+    }
+}
+
+fn decode_basic(header: &str) -> Option<(String, String)> {
+    let (scheme, encoded) = header.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(BASE64.decode(encoded.trim()).ok()?).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use argon2::password_hash::{PasswordHasher, SaltString};
+
+    fn basic(user: &str, password: &str) -> String {
+        format!("Basic {}", BASE64.encode(format!("{}:{}", user, password)))
+    }
+
+    fn auth() -> ProxyAuth {
+        let bcrypt_hash = bcrypt::hash("hunter2", 4).unwrap();
+        let argon2_hash = Argon2::default()
+            .hash_password(
+                b"correct horse",
+                &SaltString::from_b64("c29tZXNhbHRzb21lc2FsdA").unwrap(),
+            )
+            .unwrap()
+            .to_string();
+        let credentials = format!(
+            "# lab users\nalice:{}\n\nbob:{}\n",
+            bcrypt_hash, argon2_hash
+        );
+        ProxyAuth::parse(&credentials, DEFAULT_REALM).unwrap()
+    }
+
+    #[tokio::test]
+    async fn accepts_bcrypt_and_argon2_users() {
+        let auth = auth();
+        assert_eq!(
+            auth.authenticate(Some(&basic("alice", "hunter2"))).await,
+            Some("alice".to_string())
+        );
+        assert_eq!(
+            auth.authenticate(Some(&basic("bob", "correct horse")))
+                .await,
+            Some("bob".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_wrong_or_missing_credentials() {
+        let auth = auth();
+        assert_eq!(auth.authenticate(None).await, None);
+        assert_eq!(
+            auth.authenticate(Some(&basic("alice", "hunter3"))).await,
+            None
+        );
+        assert_eq!(
+            auth.authenticate(Some(&basic("mallory", "hunter2"))).await,
+            None
+        );
+        assert_eq!(auth.authenticate(Some("Bearer abc")).await, None);
+    }
+
+    #[tokio::test]
+    async fn remembers_accepted_headers() {
+        let auth = auth();
+        let header = basic("alice", "hunter2");
+        auth.authenticate(Some(&header)).await;
+        assert!(auth.remembered.lock().unwrap().contains_key(&header));
+    }
+
+    #[test]
+    fn dummy_hash_is_a_real_hash() {
+        assert_eq!(
+            Hash::parse(DUMMY_HASH),
+            Ok(Hash::Bcrypt(DUMMY_HASH.to_string()))
+        );
+        assert!(bcrypt::verify("", DUMMY_HASH).is_ok());
+    }
+
+    #[test]
+    fn decode_basic_splits_on_first_colon() {
+        assert_eq!(
+            decode_basic(&basic("alice", "a:b")),
+            Some(("alice".to_string(), "a:b".to_string()))
+        );
+    }
+
+    #[test]
+    fn parse_rejects_unknown_hashes() {
+        assert!(ProxyAuth::parse("alice:{SHA}abc", DEFAULT_REALM).is_err());
+        assert!(ProxyAuth::parse("alice", DEFAULT_REALM).is_err());
+    }
+}
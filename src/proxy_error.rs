@@ -9,7 +9,7 @@ pub struct ProxyError {
 impl ProxyError {
     pub fn new(message: &str) -> ProxyError {
         ProxyError {
-            message: message.to_string()
+            message: message.to_string(),
         }
     }
 }
@@ -0,0 +1,223 @@
+use std::error::Error;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+// a load balancer sends the header straight away, so anyone slower than this
+// isn't one
+pub const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+// the longest possible v1 header, CRLF included
+const V1_MAX_LENGTH: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+type ProtocolError = Box<dyn Error + Send + Sync>;
+
+// Reads a PROXY protocol (v1 or v2) header from the front of the stream and
+// returns the client address it gives. None means the balancer connected on
+// its own behalf (a health check, say) or didn't know the address. Reads
+// exactly the header, leaving the rest of the stream for HTTP.
+pub async fn read_header<S>(stream: &mut S) -> Result<Option<SocketAddr>, ProtocolError>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut prefix = [0u8; 6];
+    stream.read_exact(&mut prefix).await?;
+
+    if prefix == V1_PREFIX {
+        read_v1(stream).await
+    } else if prefix == V2_SIGNATURE[..6] {
+        read_v2(stream).await
+    } else {
+        Err("connection didn't start with a PROXY protocol header".into())
+    }
+}
+
+async fn read_v1<S>(stream: &mut S) -> Result<Option<SocketAddr>, ProtocolError>
+where
+    S: AsyncRead + Unpin,
+{
+    // a byte at a time, so we don't read past the end of the line
+    let mut line = Vec::from(V1_PREFIX);
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            return Err("PROXY protocol v1 header was too long".into());
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[V1_PREFIX.len()..line.len() - 2])
+        .map_err(|_| "PROXY protocol v1 header wasn't text")?;
+    parse_v1(line)
+}
+
+fn parse_v1(line: &str) -> Result<Option<SocketAddr>, ProtocolError> {
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields.as_slice() {
+        ["UNKNOWN", ..] => Ok(None),
+        [family @ ("TCP4" | "TCP6"), source, _destination, source_port, _destination_port] => {
+            let ip: IpAddr = source.parse()?;
+            if ip.is_ipv4() != (*family == "TCP4") {
+                return Err(format!("{} is not a {} address", source, family).into());
+            }
+            Ok(Some(SocketAddr::new(ip, source_port.parse()?)))
+        }
+        _ => Err(format!("PROXY protocol v1 header was not valid: {:?}", line).into()),
+    }
+}
+
+async fn read_v2<S>(stream: &mut S) -> Result<Option<SocketAddr>, ProtocolError>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut rest = [0u8; 10];
+    stream.read_exact(&mut rest).await?;
+    if rest[..6] != V2_SIGNATURE[6..] {
+        return Err("connection didn't start with a PROXY protocol header".into());
+    }
+
+    let version_command = rest[6];
+    let family = rest[7];
+    let length = u16::from_be_bytes([rest[8], rest[9]]) as usize;
+    let mut addresses = vec![0u8; length];
+    stream.read_exact(&mut addresses).await?;
+
+    if version_command >> 4 != 2 {
+        return Err(format!("unknown PROXY protocol version {}", version_command >> 4).into());
+    }
+    match version_command & 0x0f {
+        // LOCAL
+        0 => return Ok(None),
+        // PROXY
+        1 => {}
+        command => return Err(format!("unknown PROXY protocol command {}", command).into()),
+    }
+    parse_v2_addresses(family, &addresses)
+}
+
+// Only the source address matters to us. Anything after the addresses is
+// TLVs, which we skip.
+fn parse_v2_addresses(family: u8, addresses: &[u8]) -> Result<Option<SocketAddr>, ProtocolError> {
+    let too_short = || -> ProtocolError { "PROXY protocol v2 addresses were cut short".into() };
+    match family >> 4 {
+        // AF_INET
+        1 => {
+            let bytes: [u8; 12] = addresses.get(..12).ok_or_else(too_short)?.try_into()?;
+            let ip = Ipv4Addr::from([bytes[0], bytes[1], bytes[2], bytes[3]]);
+            let port = u16::from_be_bytes([bytes[8], bytes[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        // AF_INET6
+        2 => {
+            let bytes = addresses.get(..36).ok_or_else(too_short)?;
+            let ip: [u8; 16] = bytes[..16].try_into()?;
+            let port = u16::from_be_bytes([bytes[32], bytes[33]]);
+            Ok(Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(ip)), port)))
+        }
+        // AF_UNSPEC, AF_UNIX
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(header: &[u8]) -> Result<Option<SocketAddr>, ProtocolError> {
+        let mut stream = header;
+        read_header(&mut stream).await
+    }
+
+    fn v2_header(version_command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = Vec::from(V2_SIGNATURE);
+        header.push(version_command);
+        header.push(family);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    #[tokio::test]
+    async fn v1_tcp4() {
+        assert_eq!(
+            read(b"PROXY TCP4 192.0.2.7 10.0.0.1 51234 3080\r\n")
+                .await
+                .unwrap(),
+            "192.0.2.7:51234".parse().ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn v1_tcp6() {
+        assert_eq!(
+            read(b"PROXY TCP6 2001:db8::7 2001:db8::1 51234 3080\r\n")
+                .await
+                .unwrap(),
+            "[2001:db8::7]:51234".parse().ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn v1_unknown() {
+        assert_eq!(read(b"PROXY UNKNOWN\r\n").await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn v1_leaves_the_request_unread() {
+        let mut stream: &[u8] = b"PROXY TCP4 192.0.2.7 10.0.0.1 1 2\r\nGET / HTTP/1.1\r\n";
+        read_header(&mut stream).await.unwrap();
+        assert_eq!(stream, b"GET / HTTP/1.1\r\n");
+    }
+
+    #[tokio::test]
+    async fn v1_rejects_mismatched_family() {
+        assert!(read(b"PROXY TCP4 2001:db8::7 10.0.0.1 1 2\r\n")
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn v1_rejects_overlong_header() {
+        let mut header = Vec::from(&b"PROXY TCP4 "[..]);
+        header.extend_from_slice(&[b'1'; 200]);
+        assert!(read(&header).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn v2_tcp4() {
+        let header = v2_header(
+            0x21,
+            0x11,
+            &[192, 0, 2, 7, 10, 0, 0, 1, 0xc8, 0x22, 0x0c, 0x08],
+        );
+        assert_eq!(read(&header).await.unwrap(), "192.0.2.7:51234".parse().ok());
+    }
+
+    #[tokio::test]
+    async fn v2_tcp6_with_tlvs() {
+        let mut addresses = vec![0u8; 36];
+        addresses[0] = 0x20;
+        addresses[1] = 0x01;
+        addresses[15] = 7;
+        addresses[32..34].copy_from_slice(&443u16.to_be_bytes());
+        addresses.extend_from_slice(&[0x04, 0x00, 0x01, 0x00]);
+        let header = v2_header(0x21, 0x21, &addresses);
+        assert_eq!(read(&header).await.unwrap(), "[2001::7]:443".parse().ok());
+    }
+
+    #[tokio::test]
+    async fn v2_local_has_no_address() {
+        assert_eq!(read(&v2_header(0x20, 0x00, &[])).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn v2_rejects_short_addresses() {
+        assert!(read(&v2_header(0x21, 0x11, &[192, 0, 2])).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn rejects_plain_http() {
+        assert!(read(b"GET / HTTP/1.1\r\n\r\n").await.is_err());
+    }
+}
@@ -0,0 +1,957 @@
+use crate::config::env_parse;
+use crate::disk_cache::DiskCache;
+
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::header::{
+    HeaderMap, HeaderName, HeaderValue, AGE, AUTHORIZATION, CACHE_CONTROL, DATE, ETAG, EXPIRES,
+    IF_MATCH, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE, IF_UNMODIFIED_SINCE, LAST_MODIFIED,
+    PRAGMA, RANGE, SET_COOKIE, VARY,
+};
+use hyper::http::response;
+use hyper::{Method, Request, Response, StatusCode, Uri};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+pub const DEFAULT_MEMORY_LIMIT: usize = 64 * 1024 * 1024;
+pub const DEFAULT_MAX_OBJECT_SIZE: usize = 4 * 1024 * 1024;
+
+// responses with a Last-Modified but nothing saying how long they keep are
+// taken to be fresh for a tenth of their age, up to a day
+const MAX_HEURISTIC_FRESHNESS: Duration = Duration::from_secs(24 * 60 * 60);
+
+// headers, key and bookkeeping, roughly, on top of the body
+const ENTRY_OVERHEAD: usize = 256;
+
+// statuses that can be stored without being asked to (RFC 9111 section 4.2.2),
+// less 206 since we don't put ranges back together
+const STORABLE_STATUSES: &[u16] = &[200, 203, 204, 300, 301, 308, 404, 405, 410, 414, 501];
+
+// The Cache-Control directives we act on, from a request or a response.
+// Pragma: no-cache, which is all some old browsers send, counts as no-cache.
+#[derive(Debug, PartialEq, Default)]
+struct CacheControl {
+    no_store: bool,
+    no_cache: bool,
+    private: bool,
+    max_age: Option<u64>,
+    s_maxage: Option<u64>,
+    stale_if_error: Option<u64>,
+}
+
+impl CacheControl {
+    fn from_headers(headers: &HeaderMap) -> CacheControl {
+        let mut directives = CacheControl::default();
+        let values = headers
+            .get_all(CACHE_CONTROL)
+            .iter()
+            .filter_map(|value| value.to_str().ok());
+        for directive in values.flat_map(|value| value.split(',')) {
+            let (name, argument) = match directive.split_once('=') {
+                Some((name, argument)) => (name, Some(argument.trim().trim_matches('"'))),
+                None => (directive, None),
+            };
+            let seconds = argument.and_then(|argument| argument.parse().ok());
+            match name.trim().to_ascii_lowercase().as_str() {
+                "no-store" => directives.no_store = true,
+                "no-cache" => directives.no_cache = true,
+                "private" => directives.private = true,
+                "max-age" => directives.max_age = seconds,
+                "s-maxage" => directives.s_maxage = seconds,
+                "stale-if-error" => directives.stale_if_error = seconds,
+                _ => {}
+            }
+        }
+        let pragma = headers
+            .get_all(PRAGMA)
+            .iter()
+            .filter_map(|value| value.to_str().ok());
+        for value in pragma {
+            if value.to_ascii_lowercase().contains("no-cache") {
+                directives.no_cache = true;
+            }
+        }
+        directives
+    }
+}
+
+fn http_date(headers: &HeaderMap, name: HeaderName) -> Option<SystemTime> {
+    httpdate::parse_http_date(headers.get(name)?.to_str().ok()?).ok()
+}
+
+// How long a response stays fresh for, going by (in order) its s-maxage,
+// max-age, Expires and Last-Modified. Zero for responses that must always be
+// revalidated.
+fn freshness_lifetime(headers: &HeaderMap, now: SystemTime) -> Duration {
+    let directives = CacheControl::from_headers(headers);
+    if directives.no_cache {
+        return Duration::ZERO;
+    }
+    if let Some(seconds) = directives.s_maxage.or(directives.max_age) {
+        return Duration::from_secs(seconds);
+    }
+    let date = http_date(headers, DATE).unwrap_or(now);
+    if headers.contains_key(EXPIRES) {
+        // an Expires that doesn't parse is in the past
+        return http_date(headers, EXPIRES)
+            .and_then(|expires| expires.duration_since(date).ok())
+            .unwrap_or(Duration::ZERO);
+    }
+    match http_date(headers, LAST_MODIFIED) {
+        Some(last_modified) => date
+            .duration_since(last_modified)
+            .map(|age| (age / 10).min(MAX_HEURISTIC_FRESHNESS))
+            .unwrap_or(Duration::ZERO),
+        None => Duration::ZERO,
+    }
+}
+
+// the cache key for a request from a client with the given profile, since
+// each profile gets differently rewritten pages
+pub fn key(profile: &str, uri: &Uri) -> String {
+    format!("{} {}", profile, uri)
+}
+
+// Conditional request headers, which we answer ourselves for anything we
+// store rather than passing the origin's 304 on
+pub fn remove_conditionals(headers: &mut HeaderMap) {
+    headers.remove(IF_NONE_MATCH);
+    headers.remove(IF_MODIFIED_SINCE);
+}
+
+// A response as it was sent to the client, rewriting and all
+#[derive(Debug)]
+pub struct CachedResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+    // the request headers named by Vary, and what they were
+    pub vary: Vec<(HeaderName, Option<HeaderValue>)>,
+    // the origin's, for revalidating - the ETag in the headers is ours if the
+    // body was rewritten
+    pub etag: Option<HeaderValue>,
+    pub last_modified: Option<HeaderValue>,
+    pub stored_at: SystemTime,
+    // how old the response already was when we got it
+    pub initial_age: Duration,
+    pub lifetime: Duration,
+}
+
+impl CachedResponse {
+    fn age(&self, now: SystemTime) -> Duration {
+        self.initial_age + now.duration_since(self.stored_at).unwrap_or(Duration::ZERO)
+    }
+
+    fn is_fresh(&self, now: SystemTime) -> bool {
+        self.age(now) < self.lifetime
+    }
+
+    fn size(&self) -> usize {
+        let headers: usize = self
+            .headers
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len())
+            .sum();
+        self.body.len() + headers + ENTRY_OVERHEAD
+    }
+
+    pub fn matches(&self, req_headers: &HeaderMap) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, value)| req_headers.get(name) == value.as_ref())
+    }
+
+    // asks the origin for a 304 if our copy is still good
+    pub fn add_validators(&self, headers: &mut HeaderMap) {
+        remove_conditionals(headers);
+        if let Some(etag) = &self.etag {
+            headers.insert(IF_NONE_MATCH, etag.clone());
+        }
+        if let Some(last_modified) = &self.last_modified {
+            headers.insert(IF_MODIFIED_SINCE, last_modified.clone());
+        }
+    }
+
+    // whether the client's own conditional headers say it already has this
+    fn not_modified_for(&self, req_headers: &HeaderMap) -> bool {
+        if self.status != StatusCode::OK {
+            return false;
+        }
+        if let Some(if_none_match) = req_headers.get(IF_NONE_MATCH) {
+            let etag = self.headers.get(ETAG).and_then(|etag| etag.to_str().ok());
+            let Some(etag) = etag else {
+                return false;
+            };
+            let Ok(if_none_match) = if_none_match.to_str() else {
+                return false;
+            };
+            // weak comparison, as for GET
+            let etag = etag.trim_start_matches("W/");
+            return if_none_match.split(',').any(|tag| {
+                let tag = tag.trim();
+                tag == "*" || tag.trim_start_matches("W/") == etag
+            });
+        }
+        match (
+            http_date(req_headers, IF_MODIFIED_SINCE),
+            self.last_modified
+                .as_ref()
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| httpdate::parse_http_date(value).ok()),
+        ) {
+            (Some(since), Some(last_modified)) => last_modified <= since,
+            _ => false,
+        }
+    }
+
+    pub fn respond(&self, req_headers: &HeaderMap, now: SystemTime) -> Response<Full<Bytes>> {
+        let not_modified = self.not_modified_for(req_headers);
+        let mut resp = Response::new(Full::new(if not_modified {
+            Bytes::new()
+        } else {
+            self.body.clone()
+        }));
+        *resp.status_mut() = if not_modified {
+            StatusCode::NOT_MODIFIED
+        } else {
+            self.status
+        };
+        *resp.headers_mut() = self.headers.clone();
+        resp.headers_mut()
+            .insert(AGE, HeaderValue::from(self.age(now).as_secs()));
+        resp
+    }
+}
+
+// What the cache has for a request
+pub enum Lookup {
+    // not a request we cache at all
+    Bypass,
+    Miss,
+    Fresh(Arc<CachedResponse>),
+    // has to be checked with the origin before it's used
+    Stale(Arc<CachedResponse>),
+}
+
+#[derive(Default)]
+struct Entries {
+    slots: HashMap<String, Slot>,
+    // keys by when they were last used, least recently first
+    recency: BTreeMap<u64, String>,
+    clock: u64,
+    size: usize,
+}
+
+// the variants of one URL, for one profile
+struct Slot {
+    variants: Vec<Arc<CachedResponse>>,
+    last_used: u64,
+}
+
+impl Entries {
+    fn touch(&mut self, key: &str) {
+        self.clock += 1;
+        let clock = self.clock;
+        if let Some(slot) = self.slots.get_mut(key) {
+            self.recency.remove(&slot.last_used);
+            slot.last_used = clock;
+            self.recency.insert(clock, key.to_string());
+        }
+    }
+
+    fn remove_variant(&mut self, key: &str, req_headers: &HeaderMap) {
+        let Some(slot) = self.slots.get_mut(key) else {
+            return;
+        };
+        let mut freed = 0;
+        slot.variants.retain(|variant| {
+            let keep = !variant.matches(req_headers);
+            if !keep {
+                freed += variant.size();
+            }
+            keep
+        });
+        self.size -= freed;
+        if slot.variants.is_empty() {
+            self.recency.remove(&slot.last_used);
+            self.slots.remove(key);
+        }
+    }
+
+    fn evict_down_to(&mut self, limit: usize) {
+        while self.size > limit {
+            let Some((_, key)) = self.recency.pop_first() else {
+                break;
+            };
+            if let Some(slot) = self.slots.remove(&key) {
+                self.size -= slot
+                    .variants
+                    .iter()
+                    .map(|variant| variant.size())
+                    .sum::<usize>();
+            }
+        }
+    }
+}
+
+// Rewritten responses kept in memory, keyed by profile and URL and then by
+// whatever request headers they Vary on. The least recently used URLs are
+// dropped to stay within the memory limit. With a disk cache as well,
+// everything is written through to it, and what's been dropped from memory is
+// read back from there.
+pub struct ResponseCache {
+    memory_limit: usize,
+    max_object_size: usize,
+    entries: Mutex<Entries>,
+    disk: Option<Arc<DiskCache>>,
+    // how long past going stale a copy can stand in for an origin that's
+    // failing, unless the origin allows longer
+    stale_if_error: Duration,
+}
+
+impl Default for ResponseCache {
+    fn default() -> Self {
+        ResponseCache::new(DEFAULT_MEMORY_LIMIT, DEFAULT_MAX_OBJECT_SIZE)
+    }
+}
+
+impl ResponseCache {
+    pub fn new(memory_limit: usize, max_object_size: usize) -> ResponseCache {
+        ResponseCache {
+            memory_limit,
+            max_object_size,
+            entries: Mutex::new(Entries::default()),
+            disk: None,
+            stale_if_error: Duration::ZERO,
+        }
+    }
+
+    pub fn from_env() -> Result<ResponseCache, Box<dyn Error>> {
+        Ok(ResponseCache {
+            disk: DiskCache::from_env()?.map(Arc::new),
+            stale_if_error: Duration::from_secs(env_parse("CACHE_STALE_IF_ERROR")?.unwrap_or(0)),
+            ..ResponseCache::new(
+                env_parse("CACHE_MEMORY_LIMIT")?.unwrap_or(DEFAULT_MEMORY_LIMIT),
+                env_parse("CACHE_MAX_OBJECT_SIZE")?.unwrap_or(DEFAULT_MAX_OBJECT_SIZE),
+            )
+        })
+    }
+
+    // As lookup, but going to the disk cache for anything that's not in
+    // memory
+    pub async fn load<B>(&self, key: &str, req: &Request<B>, now: SystemTime) -> Lookup {
+        let lookup = self.lookup(key, req, now);
+        let (Lookup::Miss, Some(disk)) = (&lookup, &self.disk) else {
+            return lookup;
+        };
+        match disk.load(key, req.headers()).await {
+            Some(entry) => {
+                self.insert(key, req.headers(), Arc::new(entry));
+                self.lookup(key, req, now)
+            }
+            None => lookup,
+        }
+    }
+
+    // whether a stale copy can be used when the origin fails
+    pub fn usable_after_error(&self, entry: &CachedResponse, now: SystemTime) -> bool {
+        let allowed = CacheControl::from_headers(&entry.headers)
+            .stale_if_error
+            .map(Duration::from_secs)
+            .unwrap_or(Duration::ZERO)
+            .max(self.stale_if_error);
+        entry.age(now) < entry.lifetime + allowed
+    }
+
+    pub fn lookup<B>(&self, key: &str, req: &Request<B>, now: SystemTime) -> Lookup {
+        let headers = req.headers();
+        if self.memory_limit == 0
+            || req.method() != Method::GET
+            || headers.contains_key(AUTHORIZATION)
+            || headers.contains_key(RANGE)
+            || headers.contains_key(IF_RANGE)
+            || headers.contains_key(IF_MATCH)
+            || headers.contains_key(IF_UNMODIFIED_SINCE)
+        {
+            return Lookup::Bypass;
+        }
+        let directives = CacheControl::from_headers(headers);
+        if directives.no_store {
+            return Lookup::Bypass;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        let found = entries.slots.get(key).and_then(|slot| {
+            slot.variants
+                .iter()
+                .find(|variant| variant.matches(headers))
+                .cloned()
+        });
+        match found {
+            Some(entry) => {
+                entries.touch(key);
+                let max_age = directives.max_age.map(Duration::from_secs);
+                let acceptable = match max_age {
+                    Some(max_age) => entry.age(now) <= max_age,
+                    None => true,
+                };
+                if entry.is_fresh(now) && acceptable && !directives.no_cache {
+                    Lookup::Fresh(entry)
+                } else {
+                    Lookup::Stale(entry)
+                }
+            }
+            None => Lookup::Miss,
+        }
+    }
+
+    // Keeps a response if it's allowed to be shared, returning what was
+    // stored. `req_headers` are the ones the client sent, and `origin_etag`
+    // the ETag the origin sent, before any rewriting replaced it.
+    pub fn store(
+        &self,
+        key: &str,
+        req_headers: &HeaderMap,
+        parts: &response::Parts,
+        body: &Bytes,
+        origin_etag: Option<HeaderValue>,
+        now: SystemTime,
+    ) -> Option<Arc<CachedResponse>> {
+        let directives = CacheControl::from_headers(&parts.headers);
+        if !STORABLE_STATUSES.contains(&parts.status.as_u16())
+            || directives.no_store
+            || directives.private
+            || parts.headers.contains_key(SET_COOKIE)
+            || body.len() > self.max_object_size
+        {
+            return None;
+        }
+
+        let mut vary = vec![];
+        let names = parts
+            .headers
+            .get_all(VARY)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|name| !name.is_empty());
+        for name in names {
+            // a Vary of * means no two requests are alike
+            if name == "*" {
+                return None;
+            }
+            let name = HeaderName::from_bytes(name.as_bytes()).ok()?;
+            let value = req_headers.get(&name).cloned();
+            vary.push((name, value));
+        }
+
+        let entry = Arc::new(CachedResponse {
+            status: parts.status,
+            headers: parts.headers.clone(),
+            body: body.clone(),
+            vary,
+            etag: origin_etag,
+            last_modified: parts.headers.get(LAST_MODIFIED).cloned(),
+            stored_at: now,
+            initial_age: age_header(&parts.headers),
+            lifetime: freshness_lifetime(&parts.headers, now),
+        });
+        // nothing to gain from a copy that's stale straight away and can't
+        // be revalidated
+        if entry.lifetime == Duration::ZERO && entry.etag.is_none() && entry.last_modified.is_none()
+        {
+            return None;
+        }
+        self.keep(key, req_headers, entry.clone());
+        Some(entry)
+    }
+
+    // Freshens up a stale copy the origin said hasn't changed, returning the
+    // updated copy
+    pub fn revalidated(
+        &self,
+        key: &str,
+        req_headers: &HeaderMap,
+        stale: &CachedResponse,
+        not_modified: &HeaderMap,
+        now: SystemTime,
+    ) -> Arc<CachedResponse> {
+        let mut headers = stale.headers.clone();
+        for name in [CACHE_CONTROL, EXPIRES, DATE] {
+            if not_modified.contains_key(&name) {
+                headers.remove(&name);
+                for value in not_modified.get_all(&name) {
+                    headers.append(&name, value.clone());
+                }
+            }
+        }
+        let entry = Arc::new(CachedResponse {
+            status: stale.status,
+            body: stale.body.clone(),
+            vary: stale.vary.clone(),
+            etag: not_modified.get(ETAG).or(stale.etag.as_ref()).cloned(),
+            last_modified: stale.last_modified.clone(),
+            stored_at: now,
+            initial_age: age_header(not_modified),
+            lifetime: freshness_lifetime(&headers, now),
+            headers,
+        });
+        self.keep(key, req_headers, entry.clone());
+        entry
+    }
+
+    // in memory, and written to disk in the background
+    fn keep(&self, key: &str, req_headers: &HeaderMap, entry: Arc<CachedResponse>) {
+        self.insert(key, req_headers, entry.clone());
+        if let Some(disk) = &self.disk {
+            let disk = disk.clone();
+            let key = key.to_string();
+            tokio::task::spawn(async move {
+                if let Err(err) = disk.save(&key, &entry).await {
+                    println!("= couldn't write {} to the disk cache: {}", key, err);
+                }
+            });
+        }
+    }
+
+    fn insert(&self, key: &str, req_headers: &HeaderMap, entry: Arc<CachedResponse>) {
+        let size = entry.size();
+        if size > self.memory_limit {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        entries.remove_variant(key, req_headers);
+        entries
+            .slots
+            .entry(key.to_string())
+            .or_insert_with(|| Slot {
+                variants: vec![],
+                last_used: 0,
+            })
+            .variants
+            .push(entry);
+        entries.size += size;
+        entries.touch(key);
+        entries.evict_down_to(self.memory_limit);
+    }
+}
+
+fn age_header(headers: &HeaderMap) -> Duration {
+    headers
+        .get(AGE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+        .map(Duration::from_secs)
+        .unwrap_or(Duration::ZERO)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: &str = "default https://example.com/";
+
+    fn now() -> SystemTime {
+        httpdate::parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT").unwrap()
+    }
+
+    fn later(seconds: u64) -> SystemTime {
+        now() + Duration::from_secs(seconds)
+    }
+
+    fn parts(headers: &[(&str, &str)]) -> response::Parts {
+        let mut resp = Response::builder();
+        for (name, value) in headers {
+            resp = resp.header(*name, *value);
+        }
+        resp.body(()).unwrap().into_parts().0
+    }
+
+    fn get(headers: &[(&str, &str)]) -> Request<()> {
+        let mut req = Request::get("https://example.com/");
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        req.body(()).unwrap()
+    }
+
+    fn store(cache: &ResponseCache, req: &Request<()>, headers: &[(&str, &str)]) -> bool {
+        let parts = parts(headers);
+        let origin_etag = parts.headers.get(ETAG).cloned();
+        cache
+            .store(
+                KEY,
+                req.headers(),
+                &parts,
+                &Bytes::from("hello"),
+                origin_etag,
+                now(),
+            )
+            .is_some()
+    }
+
+    fn lookup(cache: &ResponseCache, req: &Request<()>, at: SystemTime) -> &'static str {
+        match cache.lookup(KEY, req, at) {
+            Lookup::Bypass => "bypass",
+            Lookup::Miss => "miss",
+            Lookup::Fresh(_) => "fresh",
+            Lookup::Stale(_) => "stale",
+        }
+    }
+
+    #[test]
+    fn parses_cache_control() {
+        let mut headers = HeaderMap::new();
+        headers.append(CACHE_CONTROL, "public, max-age=60".parse().unwrap());
+        headers.append(CACHE_CONTROL, "S-MaxAge=\"120\", no-cache".parse().unwrap());
+        assert_eq!(
+            CacheControl::from_headers(&headers),
+            CacheControl {
+                no_cache: true,
+                max_age: Some(60),
+                s_maxage: Some(120),
+                ..CacheControl::default()
+            }
+        );
+    }
+
+    #[test]
+    fn pragma_no_cache_counts() {
+        let mut headers = HeaderMap::new();
+        headers.insert(PRAGMA, "no-cache".parse().unwrap());
+        assert!(CacheControl::from_headers(&headers).no_cache);
+    }
+
+    #[test]
+    fn freshness_comes_from_max_age_then_expires_then_last_modified() {
+        let lifetime =
+            |headers: &[(&str, &str)]| freshness_lifetime(&parts(headers).headers, now());
+        assert_eq!(
+            lifetime(&[
+                ("Cache-Control", "max-age=60, s-maxage=30"),
+                ("Expires", "Sun, 06 Nov 1994 09:49:37 GMT"),
+            ]),
+            Duration::from_secs(30)
+        );
+        assert_eq!(
+            lifetime(&[
+                ("Date", "Sun, 06 Nov 1994 08:49:37 GMT"),
+                ("Expires", "Sun, 06 Nov 1994 09:49:37 GMT"),
+            ]),
+            Duration::from_secs(3600)
+        );
+        assert_eq!(lifetime(&[("Expires", "0")]), Duration::ZERO);
+        assert_eq!(
+            lifetime(&[("Last-Modified", "Sun, 06 Nov 1994 07:49:37 GMT")]),
+            Duration::from_secs(360)
+        );
+        assert_eq!(
+            lifetime(&[("Last-Modified", "Tue, 06 Nov 1984 08:49:37 GMT")]),
+            MAX_HEURISTIC_FRESHNESS
+        );
+        assert_eq!(
+            lifetime(&[("Cache-Control", "max-age=60, no-cache")]),
+            Duration::ZERO
+        );
+    }
+
+    #[test]
+    fn fresh_until_max_age_passes() {
+        let cache = ResponseCache::default();
+        let req = get(&[]);
+        assert_eq!(lookup(&cache, &req, now()), "miss");
+        assert!(store(&cache, &req, &[("Cache-Control", "max-age=60")]));
+        assert_eq!(lookup(&cache, &req, later(59)), "fresh");
+        assert_eq!(lookup(&cache, &req, later(60)), "stale");
+    }
+
+    #[test]
+    fn age_from_the_origin_counts() {
+        let cache = ResponseCache::default();
+        let req = get(&[]);
+        store(
+            &cache,
+            &req,
+            &[("Cache-Control", "max-age=60"), ("Age", "50")],
+        );
+        assert_eq!(lookup(&cache, &req, later(5)), "fresh");
+        assert_eq!(lookup(&cache, &req, later(10)), "stale");
+        let Lookup::Fresh(entry) = cache.lookup(KEY, &req, later(5)) else {
+            panic!("not fresh");
+        };
+        assert_eq!(entry.respond(req.headers(), later(5)).headers()[AGE], "55");
+    }
+
+    #[test]
+    fn clients_can_ask_for_revalidation() {
+        let cache = ResponseCache::default();
+        store(&cache, &get(&[]), &[("Cache-Control", "max-age=60")]);
+        assert_eq!(
+            lookup(&cache, &get(&[("Pragma", "no-cache")]), now()),
+            "stale"
+        );
+        assert_eq!(
+            lookup(&cache, &get(&[("Cache-Control", "max-age=0")]), later(1)),
+            "stale"
+        );
+        assert_eq!(
+            lookup(&cache, &get(&[("Cache-Control", "no-store")]), now()),
+            "bypass"
+        );
+    }
+
+    #[test]
+    fn only_plain_gets_are_looked_up() {
+        let cache = ResponseCache::default();
+        let post = Request::post("https://example.com/").body(()).unwrap();
+        assert_eq!(lookup(&cache, &post, now()), "bypass");
+        assert_eq!(
+            lookup(&cache, &get(&[("Range", "bytes=0-1")]), now()),
+            "bypass"
+        );
+        assert_eq!(
+            lookup(&cache, &get(&[("Authorization", "Basic eDp5")]), now()),
+            "bypass"
+        );
+        let disabled = ResponseCache::new(0, DEFAULT_MAX_OBJECT_SIZE);
+        assert_eq!(lookup(&disabled, &get(&[]), now()), "bypass");
+    }
+
+    #[test]
+    fn unshareable_responses_are_not_stored() {
+        let cache = ResponseCache::default();
+        let req = get(&[]);
+        assert!(!store(&cache, &req, &[("Cache-Control", "no-store")]));
+        assert!(!store(
+            &cache,
+            &req,
+            &[("Cache-Control", "private, max-age=60")]
+        ));
+        assert!(!store(
+            &cache,
+            &req,
+            &[("Cache-Control", "max-age=60"), ("Set-Cookie", "a=b")]
+        ));
+        assert!(!store(
+            &cache,
+            &req,
+            &[("Cache-Control", "max-age=60"), ("Vary", "*")]
+        ));
+        // nothing to go on
+        assert!(!store(&cache, &req, &[]));
+        assert_eq!(lookup(&cache, &req, now()), "miss");
+
+        let small = ResponseCache::new(DEFAULT_MEMORY_LIMIT, 4);
+        assert!(!store(&small, &req, &[("Cache-Control", "max-age=60")]));
+    }
+
+    #[test]
+    fn validators_alone_are_worth_keeping() {
+        let cache = ResponseCache::default();
+        let req = get(&[]);
+        assert!(store(&cache, &req, &[("ETag", "\"v1\"")]));
+        let Lookup::Stale(entry) = cache.lookup(KEY, &req, now()) else {
+            panic!("not stale");
+        };
+        let mut headers = get(&[("If-Modified-Since", "Sun, 06 Nov 1994 08:49:37 GMT")])
+            .headers()
+            .clone();
+        entry.add_validators(&mut headers);
+        assert_eq!(headers[IF_NONE_MATCH], "\"v1\"");
+        assert!(!headers.contains_key(IF_MODIFIED_SINCE));
+    }
+
+    #[test]
+    fn variants_are_kept_apart() {
+        let cache = ResponseCache::default();
+        let english = get(&[("Accept-Language", "en")]);
+        let french = get(&[("Accept-Language", "fr")]);
+        store(
+            &cache,
+            &english,
+            &[("Cache-Control", "max-age=60"), ("Vary", "Accept-Language")],
+        );
+        assert_eq!(lookup(&cache, &english, now()), "fresh");
+        assert_eq!(lookup(&cache, &french, now()), "miss");
+        assert_eq!(lookup(&cache, &get(&[]), now()), "miss");
+        store(
+            &cache,
+            &french,
+            &[("Cache-Control", "max-age=60"), ("Vary", "Accept-Language")],
+        );
+        assert_eq!(lookup(&cache, &english, now()), "fresh");
+        assert_eq!(lookup(&cache, &french, now()), "fresh");
+    }
+
+    #[test]
+    fn answers_conditional_requests() {
+        let cache = ResponseCache::default();
+        let entry = cache
+            .store(
+                KEY,
+                &HeaderMap::new(),
+                &parts(&[
+                    ("Cache-Control", "max-age=60"),
+                    ("ETag", "W/\"v1\""),
+                    ("Last-Modified", "Sun, 06 Nov 1994 07:00:00 GMT"),
+                ]),
+                &Bytes::from("hello"),
+                Some("W/\"v1\"".parse().unwrap()),
+                now(),
+            )
+            .unwrap();
+        let status =
+            |headers: &[(&str, &str)]| entry.respond(get(headers).headers(), now()).status();
+        assert_eq!(status(&[]), StatusCode::OK);
+        assert_eq!(
+            status(&[("If-None-Match", "\"v0\", \"v1\"")]),
+            StatusCode::NOT_MODIFIED
+        );
+        assert_eq!(status(&[("If-None-Match", "\"v0\"")]), StatusCode::OK);
+        assert_eq!(
+            status(&[("If-Modified-Since", "Sun, 06 Nov 1994 07:00:00 GMT")]),
+            StatusCode::NOT_MODIFIED
+        );
+        assert_eq!(
+            status(&[("If-Modified-Since", "Sun, 06 Nov 1994 06:59:59 GMT")]),
+            StatusCode::OK
+        );
+    }
+
+    #[test]
+    fn rewritten_copies_revalidate_with_the_origins_etag() {
+        let cache = ResponseCache::default();
+        let entry = cache
+            .store(
+                KEY,
+                &HeaderMap::new(),
+                &parts(&[("ETag", "W/\"rewritten\"")]),
+                &Bytes::from("hello"),
+                Some("\"origin\"".parse().unwrap()),
+                now(),
+            )
+            .unwrap();
+        let mut headers = HeaderMap::new();
+        entry.add_validators(&mut headers);
+        assert_eq!(headers[IF_NONE_MATCH], "\"origin\"");
+        let status = |etag: &str| {
+            entry
+                .respond(get(&[("If-None-Match", etag)]).headers(), now())
+                .status()
+        };
+        assert_eq!(status("W/\"rewritten\""), StatusCode::NOT_MODIFIED);
+        assert_eq!(status("\"origin\""), StatusCode::OK);
+    }
+
+    #[test]
+    fn revalidation_refreshes_the_copy() {
+        let cache = ResponseCache::default();
+        let req = get(&[]);
+        store(
+            &cache,
+            &req,
+            &[("Cache-Control", "max-age=60"), ("ETag", "\"v1\"")],
+        );
+        let Lookup::Stale(stale) = cache.lookup(KEY, &req, later(120)) else {
+            panic!("not stale");
+        };
+        let not_modified = parts(&[("Cache-Control", "max-age=300")]).headers;
+        let fresh = cache.revalidated(KEY, req.headers(), &stale, &not_modified, later(120));
+        assert_eq!(fresh.body, Bytes::from("hello"));
+        assert_eq!(fresh.headers[CACHE_CONTROL], "max-age=300");
+        assert_eq!(lookup(&cache, &req, later(400)), "fresh");
+    }
+
+    #[test]
+    fn stale_copies_can_stand_in_for_failing_origins() {
+        let cache = ResponseCache::default();
+        let req = get(&[]);
+        let entry = |headers| {
+            cache
+                .store(
+                    KEY,
+                    req.headers(),
+                    &parts(headers),
+                    &Bytes::from("hello"),
+                    None,
+                    now(),
+                )
+                .unwrap()
+        };
+        let plain = entry(&[("Cache-Control", "max-age=60")]);
+        assert!(!cache.usable_after_error(&plain, later(61)));
+        let allowed = entry(&[("Cache-Control", "max-age=60, stale-if-error=100")]);
+        assert!(cache.usable_after_error(&allowed, later(159)));
+        assert!(!cache.usable_after_error(&allowed, later(160)));
+
+        let lenient = ResponseCache {
+            stale_if_error: Duration::from_secs(1000),
+            ..ResponseCache::default()
+        };
+        assert!(lenient.usable_after_error(&plain, later(1059)));
+        assert!(lenient.usable_after_error(&allowed, later(1059)));
+        assert!(!lenient.usable_after_error(&allowed, later(1060)));
+    }
+
+    #[tokio::test]
+    async fn copies_on_disk_are_loaded_back() {
+        let dir = std::env::temp_dir().join(format!(
+            "the-insecure-proxy-response-cache-{}",
+            std::process::id()
+        ));
+        let disk = Arc::new(DiskCache::new(dir.clone()));
+        let req = get(&[]);
+        let stored = ResponseCache::default()
+            .store(
+                KEY,
+                req.headers(),
+                &parts(&[("Cache-Control", "max-age=60")]),
+                &Bytes::from("hello"),
+                None,
+                now(),
+            )
+            .unwrap();
+        disk.save(KEY, &stored).await.unwrap();
+
+        let cache = ResponseCache {
+            disk: Some(disk),
+            ..ResponseCache::default()
+        };
+        assert_eq!(lookup(&cache, &req, later(1)), "miss");
+        let Lookup::Fresh(loaded) = cache.load(KEY, &req, later(1)).await else {
+            panic!("not loaded");
+        };
+        assert_eq!(loaded.body, Bytes::from("hello"));
+        assert_eq!(lookup(&cache, &req, later(1)), "fresh");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn least_recently_used_urls_are_evicted() {
+        let entry_size = 5 + "cache-control".len() + "max-age=60".len() + ENTRY_OVERHEAD;
+        let cache = ResponseCache::new(entry_size * 2, DEFAULT_MAX_OBJECT_SIZE);
+        let req = get(&[]);
+        let stored = |key: &str| {
+            cache.store(
+                key,
+                req.headers(),
+                &parts(&[("Cache-Control", "max-age=60")]),
+                &Bytes::from("hello"),
+                None,
+                now(),
+            )
+        };
+        stored("a");
+        stored("b");
+        // "a" is used again, so "b" goes first
+        assert!(matches!(cache.lookup("a", &req, now()), Lookup::Fresh(_)));
+        stored("c");
+        assert!(matches!(cache.lookup("a", &req, now()), Lookup::Fresh(_)));
+        assert!(matches!(cache.lookup("b", &req, now()), Lookup::Miss));
+        assert!(matches!(cache.lookup("c", &req, now()), Lookup::Fresh(_)));
+        assert_eq!(cache.entries.lock().unwrap().size, entry_size * 2);
+    }
+}
@@ -0,0 +1,339 @@
+use crate::content_type;
+use crate::html;
+
+use bytes::Bytes;
+use lol_html::element;
+use std::cell::RefCell;
+use std::error::Error;
+
+// <picture><source>s of these types are worth considering - anything else
+// (WebP, AVIF) is unlikely to work in a browser that doesn't know srcset
+const LEGACY_IMAGE_TYPES: &[&str] = &["image/jpeg", "image/gif", "image/png"];
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Descriptor {
+    Width(u32),
+    Density(f32),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Candidate {
+    pub url: String,
+    pub descriptor: Descriptor,
+}
+
+// Parses a srcset attribute, loosely following the HTML spec's algorithm.
+// Candidates with descriptors we don't understand are skipped.
+pub fn parse(srcset: &str) -> Vec<Candidate> {
+    let mut candidates = Vec::new();
+    let mut rest = srcset;
+
+    loop {
+        rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == ',');
+        if rest.is_empty() {
+            return candidates;
+        }
+
+        let url_end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        let mut url = &rest[..url_end];
+        rest = &rest[url_end..];
+
+        // "a.jpg, b.jpg 2x" - a trailing comma on the URL means no descriptor
+        let descriptor = if url.ends_with(',') {
+            url = url.trim_end_matches(',');
+            ""
+        } else {
+            let descriptor_end = descriptor_end(rest);
+            let descriptor = &rest[..descriptor_end];
+            rest = &rest[descriptor_end..];
+            descriptor
+        };
+
+        if let Some(descriptor) = parse_descriptor(descriptor) {
+            candidates.push(Candidate {
+                url: url.to_string(),
+                descriptor,
+            });
+        }
+    }
+}
+
+// commas inside parentheses don't end a descriptor
+fn descriptor_end(rest: &str) -> usize {
+    let mut depth = 0;
+    for (index, chr) in rest.char_indices() {
+        match chr {
+            '(' => depth += 1,
+            ')' if depth > 0 => depth -= 1,
+            ',' if depth == 0 => return index,
+            _ => {}
+        }
+    }
+    rest.len()
+}
+
+fn parse_descriptor(descriptor: &str) -> Option<Descriptor> {
+    let mut result = Descriptor::Density(1.0);
+    for token in descriptor.split_whitespace() {
+        if let Some(width) = token.strip_suffix('w') {
+            result = Descriptor::Width(width.parse().ok().filter(|w| *w > 0)?);
+        } else if let Some(density) = token.strip_suffix('x') {
+            result = Descriptor::Density(density.parse().ok().filter(|d: &f32| *d > 0.0)?);
+        } else if token.ends_with('h') {
+            // heights are allowed alongside widths but don't help us choose
+        } else {
+            return None;
+        }
+    }
+    Some(result)
+}
+
+// Picks the narrowest candidate that's at least target_width wide, or the
+// widest if none are. Without widths, goes for the lowest density that's at
+// least 1x - old screens aren't retina.
+pub fn choose(candidates: &[Candidate], target_width: u32) -> Option<&Candidate> {
+    let widths: Vec<(&Candidate, u32)> = candidates
+        .iter()
+        .filter_map(|candidate| match candidate.descriptor {
+            Descriptor::Width(width) => Some((candidate, width)),
+            Descriptor::Density(_) => None,
+        })
+        .collect();
+
+    if !widths.is_empty() {
+        let big_enough = widths
+            .iter()
+            .filter(|(_, width)| *width >= target_width)
+            .min_by_key(|(_, width)| *width);
+        let widest = widths.iter().max_by_key(|(_, width)| *width);
+        return big_enough.or(widest).map(|(candidate, _)| *candidate);
+    }
+
+    let densities = candidates
+        .iter()
+        .filter_map(|candidate| match candidate.descriptor {
+            Descriptor::Density(density) => Some((candidate, density)),
+            Descriptor::Width(_) => None,
+        });
+    let (at_least_1x, below_1x): (Vec<_>, Vec<_>) = densities.partition(|(_, d)| *d >= 1.0);
+    at_least_1x
+        .into_iter()
+        .min_by(|(_, a), (_, b)| a.total_cmp(b))
+        .or_else(|| below_1x.into_iter().max_by(|(_, a), (_, b)| a.total_cmp(b)))
+        .map(|(candidate, _)| candidate)
+}
+
+// Replaces srcset attributes and <picture> sources with a single <img src>,
+// which is all browsers before ~2014 understand.
+pub fn rewrite(
+    body: &[u8],
+    content_type: &str,
+    target_width: u32,
+) -> Result<Bytes, Box<dyn Error>> {
+    // the <source> picked so far in the current <picture>, and whether it
+    // had a media query
+    let picture_source: RefCell<Option<(String, bool)>> = RefCell::new(None);
+
+    html::rewrite(
+        body,
+        content_type,
+        vec![
+            element!("picture", |_el| {
+                picture_source.replace(None);
+                Ok(())
+            }),
+            element!("picture source[srcset]", |el| {
+                let usable_type = el.get_attribute("type").is_none_or(|mime| {
+                    LEGACY_IMAGE_TYPES
+                        .iter()
+                        .any(|legacy| content_type::is(&mime, legacy))
+                });
+                let mut chosen = picture_source.borrow_mut();
+                let has_media = el.has_attribute("media");
+                // browsers take the first source that matches, and the first
+                // one without a media query always does, so it beats any
+                // earlier ones that had one and nothing after it counts
+                let replaces = match &*chosen {
+                    None => true,
+                    Some((_, chosen_had_media)) => *chosen_had_media && !has_media,
+                };
+                if usable_type && replaces {
+                    let candidates = parse(&el.get_attribute("srcset").unwrap_or_default());
+                    if let Some(candidate) = choose(&candidates, target_width) {
+                        *chosen = Some((candidate.url.clone(), has_media));
+                    }
+                }
+                el.remove();
+                Ok(())
+            }),
+            element!("picture img", |el| {
+                if let Some((url, _)) = picture_source.borrow_mut().take() {
+                    el.set_attribute("src", &url)?;
+                    el.remove_attribute("srcset");
+                    el.remove_attribute("sizes");
+                }
+                Ok(())
+            }),
+            element!("img[srcset]", |el| {
+                let candidates = parse(&el.get_attribute("srcset").unwrap_or_default());
+                if let Some(candidate) = choose(&candidates, target_width) {
+                    el.set_attribute("src", &candidate.url)?;
+                }
+                el.remove_attribute("srcset");
+                el.remove_attribute("sizes");
+                Ok(())
+            }),
+        ],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(url: &str, descriptor: Descriptor) -> Candidate {
+        Candidate {
+            url: url.to_string(),
+            descriptor,
+        }
+    }
+
+    fn rewrite_str(html: &str) -> String {
+        let output = rewrite(html.as_bytes(), "text/html", 640).unwrap();
+        String::from_utf8(output.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn parse_widths() {
+        assert_eq!(
+            parse("a.jpg 320w, b.jpg 1024w"),
+            vec![
+                candidate("a.jpg", Descriptor::Width(320)),
+                candidate("b.jpg", Descriptor::Width(1024)),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_densities_and_missing_descriptor() {
+        assert_eq!(
+            parse("a.jpg, b.jpg 2x"),
+            vec![
+                candidate("a.jpg", Descriptor::Density(1.0)),
+                candidate("b.jpg", Descriptor::Density(2.0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_urls_containing_commas() {
+        assert_eq!(
+            parse("/img/a,b.jpg 1x,/img/c.jpg 2x"),
+            vec![
+                candidate("/img/a,b.jpg", Descriptor::Density(1.0)),
+                candidate("/img/c.jpg", Descriptor::Density(2.0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_skips_invalid_descriptors() {
+        assert_eq!(
+            parse("a.jpg bogus, b.jpg 0w, c.jpg 100w"),
+            vec![candidate("c.jpg", Descriptor::Width(100))]
+        );
+    }
+
+    #[test]
+    fn choose_narrowest_wide_enough() {
+        let candidates = parse("a.jpg 320w, b.jpg 800w, c.jpg 1600w");
+        assert_eq!(choose(&candidates, 640).unwrap().url, "b.jpg");
+    }
+
+    #[test]
+    fn choose_widest_when_none_wide_enough() {
+        let candidates = parse("a.jpg 100w, b.jpg 200w");
+        assert_eq!(choose(&candidates, 640).unwrap().url, "b.jpg");
+    }
+
+    #[test]
+    fn choose_1x_density() {
+        let candidates = parse("b.jpg 2x, a.jpg 1x, c.jpg 0.5x");
+        assert_eq!(choose(&candidates, 640).unwrap().url, "a.jpg");
+    }
+
+    #[test]
+    fn choose_nothing_from_nothing() {
+        assert_eq!(choose(&[], 640), None);
+    }
+
+    #[test]
+    fn rewrite_img_srcset() {
+        assert_eq!(
+            rewrite_str(
+                "<img src=\"tiny.gif\" srcset=\"a.jpg 320w, b.jpg 800w\" sizes=\"50vw\" alt=\"x\">"
+            ),
+            "<img src=\"b.jpg\" alt=\"x\">"
+        );
+    }
+
+    #[test]
+    fn rewrite_img_srcset_adds_missing_src() {
+        assert_eq!(
+            rewrite_str("<img srcset=\"a.jpg 1x, b.jpg 2x\">"),
+            "<img src=\"a.jpg\">"
+        );
+    }
+
+    #[test]
+    fn rewrite_picture_uses_legacy_source() {
+        assert_eq!(
+            rewrite_str(concat!(
+                "<picture>",
+                "<source srcset=\"a.avif\" type=\"image/avif\">",
+                "<source srcset=\"a-small.jpg 400w, a-big.jpg 900w\" type=\"image/jpeg\">",
+                "<img src=\"placeholder.gif\">",
+                "</picture>"
+            )),
+            "<picture><img src=\"a-big.jpg\"></picture>"
+        );
+    }
+
+    #[test]
+    fn rewrite_picture_prefers_source_without_media_query() {
+        assert_eq!(
+            rewrite_str(concat!(
+                "<picture>",
+                "<source srcset=\"phone.jpg\" media=\"(max-width: 400px)\">",
+                "<source srcset=\"desktop.jpg\">",
+                "<img src=\"placeholder.gif\">",
+                "</picture>"
+            )),
+            "<picture><img src=\"desktop.jpg\"></picture>"
+        );
+    }
+
+    #[test]
+    fn rewrite_picture_keeps_the_first_source_without_media_query() {
+        assert_eq!(
+            rewrite_str(concat!(
+                "<picture>",
+                "<source srcset=\"first.jpg\">",
+                "<source srcset=\"second.jpg\">",
+                "<img src=\"placeholder.gif\">",
+                "</picture>"
+            )),
+            "<picture><img src=\"first.jpg\"></picture>"
+        );
+    }
+
+    #[test]
+    fn rewrite_picture_without_usable_source_keeps_img() {
+        assert_eq!(
+            rewrite_str(
+                "<picture><source srcset=\"a.webp\" type=\"image/webp\"><img src=\"a.jpg\"></picture>"
+            ),
+            "<picture><img src=\"a.jpg\"></picture>"
+        );
+    }
+}
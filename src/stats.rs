@@ -0,0 +1,55 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+// Counters shown on admin listeners, in Prometheus' text format
+#[derive(Debug, Default)]
+pub struct Stats {
+    // per listener
+    denied_clients: Mutex<BTreeMap<String, u64>>,
+}
+
+impl Stats {
+    pub fn record_denied_client(&self, listener: &str) {
+        *self
+            .denied_clients
+            .lock()
+            .unwrap()
+            .entry(listener.to_string())
+            .or_default() += 1;
+    }
+
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+        output.push_str("# TYPE the_insecure_proxy_denied_clients_total counter\n");
+        for (listener, count) in self.denied_clients.lock().unwrap().iter() {
+            let _ = writeln!(
+                output,
+                "the_insecure_proxy_denied_clients_total{{listener={:?}}} {}",
+                listener, count
+            );
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_denied_clients_per_listener() {
+        let stats = Stats::default();
+        stats.record_denied_client("default");
+        stats.record_denied_client("default");
+        stats.record_denied_client("tls");
+        assert_eq!(
+            stats.render(),
+            concat!(
+                "# TYPE the_insecure_proxy_denied_clients_total counter\n",
+                "the_insecure_proxy_denied_clients_total{listener=\"default\"} 2\n",
+                "the_insecure_proxy_denied_clients_total{listener=\"tls\"} 1\n",
+            )
+        );
+    }
+}
@@ -1,14 +1,45 @@
+use crate::admin;
+use crate::byte_range;
+use crate::charset_transcoder;
+use crate::client_profile::{ClientProfile, ClientProfiles};
+use crate::connect_tunnel::{self, ConnectPolicy};
+use crate::connection::Connection;
+use crate::destination_policy::{DestinationPolicy, GuardedResolver};
+use crate::dns_resolver::DnsResolver;
+use crate::error_page;
+use crate::forwarded::ForwardedHeaders;
+use crate::hop_by_hop::{self, Via};
+use crate::html;
+use crate::html_simplifier;
+use crate::image_transcoder;
+use crate::listener::ListenerMode;
+use crate::original_dst;
+use crate::proxy_auth::ProxyAuth;
 use crate::proxy_error::ProxyError;
+use crate::response_cache::{self, Lookup, ResponseCache};
+use crate::srcset;
+use crate::stats::Stats;
+use crate::upstream_proxy::{UpstreamConnector, UpstreamProxy};
+use crate::upstream_tls::{self, UpstreamTls, UpstreamTlsConnector};
+use crate::websocket;
 
+use blake2::{Blake2s256, Digest};
 use bytes::Bytes;
 use http_body_util::{BodyExt, Full};
 use hyper::body::Incoming;
+use hyper::header::{
+    HeaderMap, ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, HOST, IF_RANGE,
+    PROXY_AUTHENTICATE, PROXY_AUTHORIZATION, RANGE,
+};
 use hyper::http::uri::{Authority, Uri};
 use hyper::http::HeaderValue;
-use hyper::{Request, Response};
-use hyper_tls::HttpsConnector;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::client::legacy::connect::HttpConnector;
 use hyper_util::client::legacy::Client;
 use hyper_util::rt::TokioExecutor;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::SystemTime;
 
 pub const DEFAULT_REWRITTEN_MIMES: &[&str] = &[
     "text/html",
@@ -21,44 +52,472 @@ pub const DEFAULT_REWRITTEN_MIMES: &[&str] = &[
 ];
 
 pub async fn the_insecure_proxy(
-    req: Request<Incoming>,
+    proxy: Arc<TheInsecureProxy>,
+    connection: Connection,
+    mut req: Request<Incoming>,
 ) -> Result<Response<Full<Bytes>>, ProxyError> {
-    let proxy = TheInsecureProxy {
-        client: make_client(),
-        rewritten_mimes: Vec::from(DEFAULT_REWRITTEN_MIMES),
+    let listener = connection.listener.clone();
+    if !listener.acl.allows(connection.client_addr.ip()) {
+        println!(
+            "= Refusing {} {} from {} on {}",
+            req.method(),
+            req.uri(),
+            connection.client_addr,
+            listener.name
+        );
+        proxy.stats.record_denied_client(&listener.name);
+        return Ok(error_page::response(
+            StatusCode::FORBIDDEN,
+            "This proxy isn't available from your address.",
+        ));
+    }
+    if listener.mode == ListenerMode::Admin {
+        if req.uri().path() == "/prewarm" {
+            return Ok(admin::prewarm(&proxy, req).await);
+        }
+        return Ok(admin::handle(&req, &proxy.stats));
+    }
+
+    // the credentials are for us, not the origin
+    let credentials = req.headers_mut().remove(PROXY_AUTHORIZATION);
+    let user = match (&proxy.proxy_auth, listener.proxy_auth) {
+        (Some(auth), true) => {
+            let credentials = credentials.as_ref().and_then(|value| value.to_str().ok());
+            match auth.authenticate(credentials).await {
+                Some(user) => Some(user),
+                None => {
+                    println!(
+                        "= {} {} from {} needs proxy authentication",
+                        req.method(),
+                        req.uri(),
+                        connection.client_addr
+                    );
+                    return Ok(proxy_auth_required(&auth.realm));
+                }
+            }
+        }
+        _ => None,
+    };
+
+    let user_agent = req
+        .headers()
+        .get("User-Agent")
+        .and_then(|user_agent| user_agent.to_str().ok());
+    let listener_profile = listener
+        .profile
+        .as_deref()
+        .and_then(|name| Some((name, proxy.profiles.get(name)?)));
+    let (profile_name, profile) = match listener_profile {
+        Some(profile) => profile,
+        None => proxy
+            .profiles
+            .select(user_agent, connection.client_addr.ip()),
+    };
+
+    let user_note = match &user {
+        Some(user) => format!(", user {}", user),
+        None => String::new(),
+    };
+    println!(
+        "{} {} from {} (profile {}{})",
+        req.method(),
+        req.uri(),
+        connection.client_addr,
+        profile_name,
+        user_note
+    );
+    if req.method() == Method::CONNECT {
+        if listener.mode == ListenerMode::Transparent {
+            return Ok(error_page::response(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "This port doesn't do CONNECT",
+            ));
+        }
+        return Ok(connect_tunnel::handle(
+            &proxy.connect_policy,
+            &proxy.destination_policy,
+            &proxy.resolver,
+            proxy.upstream_proxy.as_deref(),
+            req,
+        )
+        .await);
+    }
+    if listener.mode == ListenerMode::Explicit && req.uri().authority().is_none() {
+        return Ok(error_page::response(
+            StatusCode::BAD_REQUEST,
+            "This is a proxy - requests need an absolute URL",
+        ));
+    }
+    host_from_uri(&mut req);
+
+    let original = connection.original_dst;
+    let req = original_dst::apply(listener.original_dst, original, &proxy.resolver, req);
+    let req = match req.await {
+        Ok(req) => req,
+        Err(err) => {
+            println!("= Refusing request: {}", err);
+            return Ok(error_page::response(
+                StatusCode::MISDIRECTED_REQUEST,
+                "That host isn't where this connection was going",
+            ));
+        }
     };
 
-    println!("{} {}", req.method(), req.uri());
-    let res = proxy.proxy_request(req).await.map_err(|err| {
-        println!("  ERR {}", err);
-        ProxyError::new("meh")
-    });
-    res
+    if destination(&req).is_none() {
+        return Ok(error_page::response(
+            StatusCode::BAD_REQUEST,
+            "Requests need a Host header or an absolute URL",
+        ));
+    }
+    if let Err(err) = proxy.check_destination(&req).await {
+        println!("= Refusing request: {}", err);
+        return Ok(error_page::response(
+            StatusCode::FORBIDDEN,
+            "This proxy won't fetch pages from that address.",
+        ));
+    }
+
+    let result = if websocket::is_upgrade(req.headers()) {
+        proxy.proxy_websocket(req, &connection).await
+    } else {
+        proxy
+            .proxy_request(req, Some(&connection), profile_name, profile)
+            .await
+    };
+    match result {
+        Ok(res) => Ok(res),
+        Err(err) => {
+            println!("  ERR {}", err);
+            match upstream_tls::describe_error(err.as_ref()) {
+                Some(reason) => Ok(error_page::response(
+                    StatusCode::BAD_GATEWAY,
+                    &format!("The secure connection to the site failed: {}", reason),
+                )),
+                None => match err.downcast_ref::<hyper_util::client::legacy::Error>() {
+                    Some(err) if err.is_connect() => Ok(error_page::response(
+                        StatusCode::BAD_GATEWAY,
+                        "The site couldn't be reached.",
+                    )),
+                    _ => Err(ProxyError::new("meh")),
+                },
+            }
+        }
+    }
+}
+
+fn proxy_auth_required(realm: &str) -> Response<Full<Bytes>> {
+    let mut response = error_page::response(
+        StatusCode::PROXY_AUTHENTICATION_REQUIRED,
+        "You need to log in to use this proxy.",
+    );
+    let challenge = format!("Basic realm=\"{}\"", realm.replace(['"', '\\'], ""));
+    if let Ok(challenge) = HeaderValue::from_str(&challenge) {
+        response.headers_mut().insert(PROXY_AUTHENTICATE, challenge);
+    }
+    response
+}
+
+// An absolute URL says where a request is going, whatever its Host header
+// says - and HTTP/1.0 browsers don't always send one - so the Host is set
+// from the URL. A port that's the default for the URL's scheme is left off,
+// as the page is fetched over HTTPS either way.
+fn host_from_uri<B>(req: &mut Request<B>) {
+    let Some(authority) = req.uri().authority() else {
+        return;
+    };
+    let default_port = match req.uri().scheme_str() {
+        Some("https") => 443,
+        _ => 80,
+    };
+    let host = match authority.port_u16() {
+        Some(port) if port != default_port => format!("{}:{}", authority.host(), port),
+        _ => authority.host().to_string(),
+    };
+    if let Ok(host) = HeaderValue::from_str(&host) {
+        req.headers_mut().insert(HOST, host);
+    }
+}
+
+// the host and port a request will be fetched from - always over HTTPS, so
+// 443 unless the Host header says otherwise
+fn destination<B>(req: &Request<B>) -> Option<(String, u16)> {
+    let host = req.headers().get("Host")?.to_str().ok()?;
+    let authority = host.parse::<Authority>().ok()?;
+    Some((
+        authority.host().to_string(),
+        authority.port_u16().unwrap_or(443),
+    ))
 }
 
-fn make_client() -> Client<HttpsConnector<hyper_util::client::legacy::connect::HttpConnector>, Full<Bytes>> {
-    let https = HttpsConnector::new();
-    Client::builder(TokioExecutor::new()).build(https)
+// Responses to HEAD, and 1xx, 204 and 304 ones, never have a body, whatever
+// their Content-Length and Content-Type say about the one a GET would get
+fn has_body(method: &Method, status: StatusCode) -> bool {
+    method != Method::HEAD
+        && !status.is_informational()
+        && status != StatusCode::NO_CONTENT
+        && status != StatusCode::NOT_MODIFIED
+}
+
+// the request again, minus the Range and anything qualifying it
+fn without_range<B>(req: &Request<B>) -> Request<Full<Bytes>> {
+    let mut whole = Request::new(Full::new(Bytes::new()));
+    *whole.method_mut() = req.method().clone();
+    *whole.uri_mut() = req.uri().clone();
+    *whole.version_mut() = req.version();
+    *whole.headers_mut() = req.headers().clone();
+    whole.headers_mut().remove(RANGE);
+    whole.headers_mut().remove(IF_RANGE);
+    whole
+}
+
+// The origin's validators and byte ranges describe what it sent, not what
+// we're sending instead. The new ETag is weak, since the same page can be
+// rewritten slightly differently as the proxy changes.
+fn mark_rewritten(headers: &mut HeaderMap, body: &Bytes) {
+    let digest: String = Blake2s256::digest(body)[..16]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    headers.insert(
+        ETAG,
+        HeaderValue::from_str(&format!("W/\"{}\"", digest)).unwrap(),
+    );
+    headers.insert(CONTENT_LENGTH, HeaderValue::from(body.len()));
+    headers.remove("Content-MD5");
+    headers.remove(ACCEPT_RANGES);
+    headers.remove(CONTENT_RANGE);
+}
+
+type UpstreamClient = Client<UpstreamTlsConnector, Full<Bytes>>;
+
+// the client for ordinary requests, and one for WebSocket upgrades
+fn make_clients(
+    resolver: &DnsResolver,
+    destination_policy: &Arc<DestinationPolicy>,
+    upstream_proxy: &Option<Arc<UpstreamProxy>>,
+    upstream_tls: &UpstreamTls,
+) -> Result<(UpstreamClient, UpstreamClient), Box<dyn Error>> {
+    let mut http = HttpConnector::new_with_resolver(GuardedResolver::new(
+        resolver.clone(),
+        destination_policy.clone(),
+    ));
+    http.enforce_http(false);
+    let upstream = UpstreamConnector::new(http, upstream_proxy.clone());
+    let https = upstream_tls.connector(upstream)?;
+    Ok((
+        Client::builder(TokioExecutor::new()).build(https.clone()),
+        Client::builder(TokioExecutor::new()).build(https.http1_only()),
+    ))
 }
 
 pub struct TheInsecureProxy {
-    client: Client<HttpsConnector<hyper_util::client::legacy::connect::HttpConnector>, Full<Bytes>>,
+    client: UpstreamClient,
+    websocket_client: UpstreamClient,
     rewritten_mimes: Vec<&'static str>,
+    profiles: ClientProfiles,
+    connect_policy: ConnectPolicy,
+    destination_policy: Arc<DestinationPolicy>,
+    resolver: DnsResolver,
+    proxy_auth: Option<Arc<ProxyAuth>>,
+    upstream_proxy: Option<Arc<UpstreamProxy>>,
+    cache: ResponseCache,
+    via: Via,
+    forwarded: ForwardedHeaders,
+    pub stats: Stats,
 }
 
 impl TheInsecureProxy {
-    pub async fn proxy_request(
-        mut self,
-        req: Request<Incoming>,
+    pub fn new(profiles: ClientProfiles) -> TheInsecureProxy {
+        let destination_policy = Arc::new(DestinationPolicy::default());
+        let resolver = DnsResolver::default();
+        let (client, websocket_client) = make_clients(
+            &resolver,
+            &destination_policy,
+            &None,
+            &UpstreamTls::default(),
+        )
+        .expect("couldn't set up TLS");
+        TheInsecureProxy {
+            client,
+            websocket_client,
+            rewritten_mimes: Vec::from(DEFAULT_REWRITTEN_MIMES),
+            profiles,
+            connect_policy: ConnectPolicy::default(),
+            destination_policy,
+            resolver,
+            proxy_auth: None,
+            upstream_proxy: None,
+            cache: ResponseCache::default(),
+            via: Via::default(),
+            forwarded: ForwardedHeaders::default(),
+            stats: Stats::default(),
+        }
+    }
+
+    pub fn from_env() -> Result<TheInsecureProxy, Box<dyn Error>> {
+        let destination_policy = Arc::new(DestinationPolicy::from_env()?);
+        let resolver = DnsResolver::from_env()?;
+        let upstream_proxy = UpstreamProxy::from_env()?.map(Arc::new);
+        if let Some(upstream) = &upstream_proxy {
+            println!("= Connecting through upstream proxy {:?}", upstream);
+        }
+        let (client, websocket_client) = make_clients(
+            &resolver,
+            &destination_policy,
+            &upstream_proxy,
+            &UpstreamTls::from_env()?,
+        )?;
+        Ok(TheInsecureProxy {
+            client,
+            websocket_client,
+            connect_policy: ConnectPolicy::from_env()?,
+            destination_policy,
+            resolver,
+            proxy_auth: ProxyAuth::from_env()?.map(Arc::new),
+            upstream_proxy,
+            cache: ResponseCache::from_env()?,
+            via: Via::from_env()?,
+            forwarded: ForwardedHeaders::from_env()?,
+            ..TheInsecureProxy::new(ClientProfiles::from_env()?)
+        })
+    }
+
+    pub fn has_profile(&self, name: &str) -> bool {
+        self.profiles.get(name).is_some()
+    }
+
+    pub fn has_proxy_auth(&self) -> bool {
+        self.proxy_auth.is_some()
+    }
+
+    // Errors say why a request's destination is refused
+    pub async fn check_destination<B>(&self, req: &Request<B>) -> Result<(), String> {
+        let Some((host, _port)) = destination(req) else {
+            return Err("the request doesn't say which host it's for".to_string());
+        };
+        // an upstream proxy resolves names itself, and may be the only one
+        // that can
+        match &self.upstream_proxy {
+            Some(upstream) if upstream.applies_to(&host) => {
+                self.destination_policy.check_name(&host)
+            }
+            _ => self.destination_policy.check(&self.resolver, &host).await,
+        }
+    }
+
+    // Fetches a page into the cache the way a client with the given profile
+    // would get it, returning the status it came back with
+    pub async fn prewarm(
+        &self,
+        profile_name: &str,
+        url: &Uri,
+    ) -> Result<StatusCode, Box<dyn Error>> {
+        let profile = self
+            .profiles
+            .get(profile_name)
+            .ok_or_else(|| format!("there's no profile called {}", profile_name))?;
+        let authority = url.authority().ok_or("not an absolute URL")?;
+        let req = Request::get(url.clone())
+            .header(HOST, authority.as_str())
+            .body(())?;
+        self.check_destination(&req).await?;
+        let resp = self.proxy_request(req, None, profile_name, profile).await?;
+        Ok(resp.status())
+    }
+
+    // WebSockets skip the cache and the rewriting - there's nothing in them
+    // we'd know how to change
+    pub async fn proxy_websocket(
+        &self,
+        mut req: Request<Incoming>,
+        connection: &Connection,
+    ) -> Result<Response<Full<Bytes>>, Box<dyn Error>> {
+        let client_upgrade = hyper::upgrade::on(&mut req);
+        let req = self.httpsify(req, Some(connection))?;
+        websocket::handle(&self.websocket_client, client_upgrade, req).await
+    }
+
+    pub async fn proxy_request<B>(
+        &self,
+        req: Request<B>,
+        connection: Option<&Connection>,
+        profile_name: &str,
+        profile: &ClientProfile,
     ) -> Result<Response<Full<Bytes>>, Box<dyn std::error::Error>> {
-        let req = self.httpsify(req)?;
+        let mut req = self.httpsify(req, connection)?;
+
+        let cache_key = response_cache::key(profile_name, req.uri());
+        let req_headers = req.headers().clone();
+        let lookup = self.cache.load(&cache_key, &req, SystemTime::now()).await;
+        let cacheable = !matches!(lookup, Lookup::Bypass);
+        let stale = match lookup {
+            Lookup::Fresh(cached) => {
+                println!("= Cache hit for {}", req.uri());
+                return Ok(cached.respond(&req_headers, SystemTime::now()));
+            }
+            Lookup::Stale(cached) => {
+                println!("= Cache has a stale copy of {}, revalidating", req.uri());
+                cached.add_validators(req.headers_mut());
+                Some(cached)
+            }
+            Lookup::Miss => {
+                println!("= Cache miss for {}", req.uri());
+                response_cache::remove_conditionals(req.headers_mut());
+                None
+            }
+            Lookup::Bypass => None,
+        };
 
         self.log_headers('>', req.headers());
         let req_uri = req.uri().clone();
         let req_method = req.method().clone();
-        let resp = self.client.request(req).await?;
+        // a part of a page we rewrite would be a part of the wrong bytes, so
+        // those are fetched whole and cut up afterwards
+        let whole_req = req_headers.contains_key(RANGE).then(|| without_range(&req));
+        let resp = self.client.request(req).await;
+
+        if let Some(stale) = &stale {
+            let now = SystemTime::now();
+            let failure = match &resp {
+                Ok(resp) if resp.status() == StatusCode::NOT_MODIFIED => {
+                    println!("= Cache revalidated {}", req_uri);
+                    let cached = self.cache.revalidated(
+                        &cache_key,
+                        &req_headers,
+                        stale,
+                        resp.headers(),
+                        now,
+                    );
+                    return Ok(cached.respond(&req_headers, now));
+                }
+                Ok(resp) if resp.status().is_server_error() => Some(resp.status().to_string()),
+                Ok(_) => None,
+                Err(err) => Some(err.to_string()),
+            };
+            if let Some(failure) = failure {
+                if self.cache.usable_after_error(stale, now) {
+                    println!(
+                        "= Origin failed ({}), serving stale copy of {}",
+                        failure, req_uri
+                    );
+                    return Ok(stale.respond(&req_headers, now));
+                }
+            }
+        }
+        let mut resp = resp?;
+        if let Some(whole_req) = whole_req {
+            if resp.status() == StatusCode::PARTIAL_CONTENT && self.changes_body(profile, &resp) {
+                println!("= Fetching all of {} to rewrite it", req_uri);
+                resp = self.client.request(whole_req).await?;
+            }
+        }
+        let has_body = has_body(&req_method, resp.status());
+        let changes_body = has_body && self.changes_body(profile, &resp);
 
         let (mut resp_parts, resp_body) = resp.into_parts();
+        hop_by_hop::remove(&mut resp_parts.headers);
+        self.via.add(&mut resp_parts.headers, resp_parts.version);
+        let origin_etag = resp_parts.headers.get(ETAG).cloned();
 
         if let Some(location) = resp_parts.headers.get("Location") {
             let new_loc = location.to_str().unwrap().replace("https://", "http://");
@@ -67,34 +526,77 @@ impl TheInsecureProxy {
                 .insert("Location", new_loc.parse().unwrap());
         }
 
-        let final_body = if let Some(content_type) = resp_parts.headers.get("Content-Type") {
+        let body_bytes = resp_body.collect().await?.to_bytes();
+        let final_body = if !has_body {
+            // the headers describe a body that isn't here, so they go back
+            // exactly as they came
+            body_bytes.clone()
+        } else if let Some(content_type) = resp_parts.headers.get("Content-Type") {
             let content_type = content_type.to_str().unwrap();
             println!("= Received content type is {}", content_type);
             if self.should_rewrite(content_type) {
                 println!("= Should rewrite!");
-                let new_body_bytes = self.rewrite_body(resp_body).await?;
+                let content_type = content_type.to_string();
+                let new_body_bytes = self.rewrite_body(Full::new(body_bytes.clone())).await?;
+                let new_body_bytes = self.rewrite_srcset(profile, &content_type, new_body_bytes);
+                let new_body_bytes = self.simplify_html(profile, &content_type, new_body_bytes);
+                let new_body_bytes = self.transcode_charset(
+                    profile,
+                    &mut resp_parts.headers,
+                    &content_type,
+                    new_body_bytes,
+                );
 
                 resp_parts.headers.insert(
                     "Content-Length",
                     HeaderValue::from_str(&new_body_bytes.len().to_string()).unwrap(),
                 );
                 self.log_headers('<', &resp_parts.headers);
-                Full::new(new_body_bytes)
+                new_body_bytes
+            } else if self.should_transcode(profile, content_type) {
+                let content_type = content_type.to_string();
+                self.transcode_image(
+                    profile,
+                    &mut resp_parts.headers,
+                    &content_type,
+                    body_bytes.clone(),
+                )
             } else {
                 println!("= not rewriting");
-                let body_bytes = resp_body.collect().await?.to_bytes();
-                Full::new(body_bytes)
+                body_bytes.clone()
             }
         } else {
-            let body_bytes = resp_body.collect().await?.to_bytes();
-            Full::new(body_bytes)
+            body_bytes.clone()
+        };
+        if final_body != body_bytes {
+            mark_rewritten(&mut resp_parts.headers, &final_body);
+        }
+        let final_body = if changes_body {
+            byte_range::apply(&req_headers, &mut resp_parts, final_body)
+        } else {
+            final_body
         };
 
         println!(
             "= Completed {} response to {} {}",
             resp_parts.status, req_method, req_uri
         );
-        Ok(Response::from_parts(resp_parts, final_body))
+        if cacheable {
+            let now = SystemTime::now();
+            let stored = self.cache.store(
+                &cache_key,
+                &req_headers,
+                &resp_parts,
+                &final_body,
+                origin_etag,
+                now,
+            );
+            if let Some(cached) = stored {
+                println!("= Cached {} ({} bytes)", req_uri, final_body.len());
+                return Ok(cached.respond(&req_headers, now));
+            }
+        }
+        Ok(Response::from_parts(resp_parts, Full::new(final_body)))
     }
 
     fn should_rewrite(&self, content_type: &str) -> bool {
@@ -108,6 +610,146 @@ impl TheInsecureProxy {
             .any(|mime| response_mime.eq_ignore_ascii_case(mime))
     }
 
+    fn should_transcode(&self, profile: &ClientProfile, content_type: &str) -> bool {
+        profile.image_transcoding.is_some()
+            && image_transcoder::source_format(content_type).is_some()
+    }
+
+    // whether we might send different bytes to the ones in the response
+    fn changes_body<B>(&self, profile: &ClientProfile, resp: &Response<B>) -> bool {
+        let content_type = resp.headers().get(CONTENT_TYPE);
+        match content_type.and_then(|value| value.to_str().ok()) {
+            Some(content_type) => {
+                self.should_rewrite(content_type) || self.should_transcode(profile, content_type)
+            }
+            None => false,
+        }
+    }
+
+    // swaps the image for a legacy-friendly one, falling back to the original
+    // bytes if it can't (or needn't) be transcoded
+    fn transcode_image(
+        &self,
+        profile: &ClientProfile,
+        headers: &mut hyper::header::HeaderMap,
+        content_type: &str,
+        body_bytes: Bytes,
+    ) -> Bytes {
+        let options = match &profile.image_transcoding {
+            Some(options) => options,
+            None => return body_bytes,
+        };
+
+        match image_transcoder::transcode(&body_bytes, content_type, options) {
+            Ok(Some(transcoded)) => {
+                println!(
+                    "= Transcoded {} to {}",
+                    content_type, transcoded.content_type
+                );
+                headers.insert(
+                    "Content-Type",
+                    HeaderValue::from_static(transcoded.content_type),
+                );
+                headers.insert(
+                    "Content-Length",
+                    HeaderValue::from_str(&transcoded.body.len().to_string()).unwrap(),
+                );
+                transcoded.body
+            }
+            Ok(None) => {
+                println!("= not transcoding {}", content_type);
+                body_bytes
+            }
+            Err(err) => {
+                println!("= failed to transcode {}: {}", content_type, err);
+                body_bytes
+            }
+        }
+    }
+
+    // old browsers only look at <img src>, so give them the best image out of
+    // any srcset or <picture>
+    fn rewrite_srcset(
+        &self,
+        profile: &ClientProfile,
+        content_type: &str,
+        body_bytes: Bytes,
+    ) -> Bytes {
+        let target_width = match profile.srcset_target_width {
+            Some(target_width) if html::is_html(content_type) => target_width,
+            _ => return body_bytes,
+        };
+
+        match srcset::rewrite(&body_bytes, content_type, target_width) {
+            Ok(rewritten) => rewritten,
+            Err(err) => {
+                println!("= failed to rewrite srcsets: {}", err);
+                body_bytes
+            }
+        }
+    }
+
+    fn simplify_html(
+        &self,
+        profile: &ClientProfile,
+        content_type: &str,
+        body_bytes: Bytes,
+    ) -> Bytes {
+        let options = match &profile.html_simplification {
+            Some(options) if html::is_html(content_type) => options,
+            _ => return body_bytes,
+        };
+
+        match html_simplifier::simplify(&body_bytes, content_type, options) {
+            Ok(simplified) => {
+                println!(
+                    "= Simplified HTML from {} to {} bytes",
+                    body_bytes.len(),
+                    simplified.len()
+                );
+                simplified
+            }
+            Err(err) => {
+                println!("= failed to simplify HTML: {}", err);
+                body_bytes
+            }
+        }
+    }
+
+    // re-encodes text into the charset the client understands, falling back
+    // to the original bytes if that fails
+    fn transcode_charset(
+        &self,
+        profile: &ClientProfile,
+        headers: &mut hyper::header::HeaderMap,
+        content_type: &str,
+        body_bytes: Bytes,
+    ) -> Bytes {
+        let target = match &profile.charset {
+            Some(target) if !content_type.starts_with("image/") => target,
+            _ => return body_bytes,
+        };
+
+        match charset_transcoder::transcode(&body_bytes, content_type, target) {
+            Ok(Some(transcoded)) => {
+                println!("= Transcoded {} to {}", content_type, target.label);
+                headers.insert(
+                    "Content-Type",
+                    HeaderValue::from_str(&transcoded.content_type).unwrap(),
+                );
+                transcoded.body
+            }
+            Ok(None) => body_bytes,
+            Err(err) => {
+                println!(
+                    "= failed to transcode {} to {}: {}",
+                    content_type, target.label, err
+                );
+                body_bytes
+            }
+        }
+    }
+
     fn log_headers(&self, prefix: char, headers: &hyper::header::HeaderMap) {
         for (key, value) in headers.iter() {
             println!("{} {:?}: {:?}", prefix, key, value);
@@ -124,21 +766,38 @@ impl TheInsecureProxy {
         let mut bytes = resp_body.collect().await?.to_bytes();
         let mut rewriter = crate::https_url_rewriter::url_rewriter();
         rewriter.consume_str(&mut bytes);
-        Ok(rewriter.move_output())
+        Ok(rewriter.finish())
     }
 
-    fn httpsify(
-        &mut self,
-        req: Request<Incoming>,
+    // Points a request at the origin over HTTPS, with only the headers meant
+    // for it. Prewarming has no client connection to describe.
+    fn httpsify<B>(
+        &self,
+        req: Request<B>,
+        connection: Option<&Connection>,
     ) -> Result<Request<Full<Bytes>>, Box<dyn std::error::Error>> {
         let (mut req_parts, _req_body) = req.into_parts();
 
         let mut parts = req_parts.uri.clone().into_parts();
-        let host = req_parts.headers.get("Host").unwrap().clone();
+        let host = req_parts
+            .headers
+            .get("Host")
+            .ok_or("the request has no Host header")?
+            .clone();
         parts.authority = Some(Authority::from_maybe_shared(host)?);
         parts.scheme = Some(hyper::http::uri::Scheme::HTTPS);
         let uri_replacement = Uri::from_parts(parts).expect("Uri failed to re-parse :S");
         req_parts.uri = uri_replacement;
+        hop_by_hop::remove(&mut req_parts.headers);
+        self.via.add(&mut req_parts.headers, req_parts.version);
+        if let Some(connection) = connection {
+            let proto = match connection.listener.tls {
+                true => "https",
+                false => "http",
+            };
+            let client = connection.client_addr.ip();
+            self.forwarded.apply(&mut req_parts.headers, client, proto);
+        }
 
         // For proxy we typically don't need the request body, use empty body
         Ok(Request::from_parts(req_parts, Full::new(Bytes::new())))
@@ -150,10 +809,7 @@ mod tests {
     use super::*;
 
     fn make_proxy() -> TheInsecureProxy {
-        TheInsecureProxy {
-            client: make_client(),
-            rewritten_mimes: Vec::from(DEFAULT_REWRITTEN_MIMES),
-        }
+        TheInsecureProxy::new(ClientProfiles::default())
     }
 
     #[test]
@@ -191,6 +847,111 @@ mod tests {
         assert!(make_proxy().should_rewrite("TEXT/HTML"));
     }
 
+    #[test]
+    fn should_transcode_is_off_by_default() {
+        assert!(!make_proxy().should_transcode(&ClientProfile::default(), "image/webp"));
+    }
+
+    #[test]
+    fn should_transcode_matches_webp_when_enabled() {
+        let profile = ClientProfile {
+            image_transcoding: Some(image_transcoder::ImageTranscoding {
+                target: image_transcoder::TargetFormat::Gif,
+                max_dimension: None,
+                jpeg_quality: image_transcoder::DEFAULT_JPEG_QUALITY,
+            }),
+            ..ClientProfile::default()
+        };
+        assert!(make_proxy().should_transcode(&profile, "image/webp"));
+        assert!(!make_proxy().should_transcode(&profile, "image/jpeg"));
+    }
+
+    #[test]
+    fn srcsets_are_only_collapsed_when_asked() {
+        let html = Bytes::from_static(b"<img src=\"a.jpg\" srcset=\"a-2x.jpg 2x\">");
+        let proxy = make_proxy();
+        let untouched = proxy.rewrite_srcset(&ClientProfile::default(), "text/html", html.clone());
+        assert_eq!(untouched, html);
+
+        let profile = ClientProfile {
+            srcset_target_width: Some(640),
+            ..ClientProfile::default()
+        };
+        let collapsed = proxy.rewrite_srcset(&profile, "text/html", html);
+        assert_eq!(&collapsed[..], b"<img src=\"a-2x.jpg\">");
+    }
+
+    #[test]
+    fn absolute_urls_set_the_host() {
+        let mut req = Request::get("http://example.com/page")
+            .header("Host", "elsewhere.com")
+            .body(())
+            .unwrap();
+        host_from_uri(&mut req);
+        assert_eq!(req.headers()[HOST], "example.com");
+        assert_eq!(destination(&req), Some(("example.com".to_string(), 443)));
+
+        // HTTP/1.0 browsers may not send a Host at all
+        let mut req = Request::get("http://user@example.com:8080/")
+            .version(hyper::Version::HTTP_10)
+            .body(())
+            .unwrap();
+        host_from_uri(&mut req);
+        assert_eq!(req.headers()[HOST], "example.com:8080");
+
+        let mut req = Request::get("/").body(()).unwrap();
+        host_from_uri(&mut req);
+        assert_eq!(destination(&req), None);
+    }
+
+    #[test]
+    fn destination_defaults_to_https_port() {
+        let req = Request::get("/")
+            .header("Host", "example.com")
+            .body(())
+            .unwrap();
+        assert_eq!(destination(&req), Some(("example.com".to_string(), 443)));
+
+        let req = Request::get("/")
+            .header("Host", "[::1]:8443")
+            .body(())
+            .unwrap();
+        assert_eq!(destination(&req), Some(("[::1]".to_string(), 8443)));
+    }
+
+    #[test]
+    fn rewritten_bodies_get_their_own_etag() {
+        let mut headers = HeaderMap::new();
+        headers.insert(ETAG, HeaderValue::from_static("\"origin\""));
+        headers.insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+        headers.insert("Content-MD5", HeaderValue::from_static("bm90IHJlYWxseQ=="));
+        headers.insert(CONTENT_LENGTH, HeaderValue::from_static("100"));
+        mark_rewritten(&mut headers, &Bytes::from("hello"));
+
+        let etag = headers[ETAG].to_str().unwrap().to_string();
+        assert!(etag.starts_with("W/\""), "{}", etag);
+        assert_eq!(headers[CONTENT_LENGTH], "5");
+        assert!(!headers.contains_key(ACCEPT_RANGES));
+        assert!(!headers.contains_key("Content-MD5"));
+
+        // the same bytes get the same tag, different ones don't
+        let mut again = HeaderMap::new();
+        mark_rewritten(&mut again, &Bytes::from("hello"));
+        assert_eq!(again[ETAG], etag.as_str());
+        mark_rewritten(&mut again, &Bytes::from("goodbye"));
+        assert_ne!(again[ETAG], etag.as_str());
+    }
+
+    #[test]
+    fn only_some_responses_have_bodies() {
+        assert!(has_body(&Method::GET, StatusCode::OK));
+        assert!(has_body(&Method::POST, StatusCode::NOT_FOUND));
+        assert!(!has_body(&Method::HEAD, StatusCode::OK));
+        assert!(!has_body(&Method::GET, StatusCode::NO_CONTENT));
+        assert!(!has_body(&Method::GET, StatusCode::NOT_MODIFIED));
+        assert!(!has_body(&Method::GET, StatusCode::CONTINUE));
+    }
+
     #[test]
     fn httpsify_replaces_uri_scheme() {
         // Create a request with Incoming body type by using the service function approach
@@ -205,4 +966,264 @@ mod tests {
 
         assert_eq!(expected_uri.to_string(), "https://example.com/");
     }
+
+    #[cfg(feature = "tls-interception")]
+    mod with_an_origin {
+        use super::*;
+        use crate::dns_resolver::{IpPreference, DEFAULT_CACHE_TTL};
+        use crate::forwarded::{Existing, Style};
+        use crate::listener::{ListenAddress, Listener};
+        use crate::upstream_tls::tests::handshakes::start_origin_with;
+        use hyper::header::{CONNECTION, ORIGIN, UPGRADE};
+        use hyper::http::response;
+        use hyper::server::conn::http1;
+        use hyper::service::service_fn;
+        use hyper_util::rt::TokioIo;
+        use std::net::SocketAddr;
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::{TcpListener, TcpStream};
+
+        const PAGE: &str = "<a href=\"https://example.com/\">example</a>";
+
+        // serves PAGE at /, and the same headers without it to HEAD
+        // requests; /empty is a 204, /unchanged a 304, /headers lists the
+        // request's headers, and /socket is a WebSocket that echoes whatever
+        // it's sent
+        async fn origin(mut req: Request<Incoming>) -> Response<Full<Bytes>> {
+            if req.uri().path() == "/socket" {
+                return echo_socket(&mut req);
+            }
+            let builder = Response::builder()
+                .header(CONTENT_TYPE, "text/html")
+                .header(ETAG, "\"origin\"")
+                .header("Cache-Control", "no-store");
+            let response = match req.uri().path() {
+                "/empty" => builder.status(StatusCode::NO_CONTENT).body(Bytes::new()),
+                "/unchanged" => builder.status(StatusCode::NOT_MODIFIED).body(Bytes::new()),
+                "/headers" => {
+                    let listed: String = req
+                        .headers()
+                        .iter()
+                        .map(|(name, value)| format!("{}: {}\n", name, value.to_str().unwrap()))
+                        .collect();
+                    builder.body(Bytes::from(listed))
+                }
+                _ if req.method() == Method::HEAD => builder
+                    .header(CONTENT_LENGTH, PAGE.len())
+                    .body(Bytes::new()),
+                _ => builder.body(Bytes::from(PAGE)),
+            };
+            response.unwrap().map(Full::new)
+        }
+
+        fn echo_socket(req: &mut Request<Incoming>) -> Response<Full<Bytes>> {
+            let upgrade = hyper::upgrade::on(&mut *req);
+            tokio::task::spawn(async move {
+                let socket = TokioIo::new(upgrade.await.unwrap());
+                let (mut reading, mut writing) = tokio::io::split(socket);
+                let _ = tokio::io::copy(&mut reading, &mut writing).await;
+            });
+            let seen_origin = req.headers().get(ORIGIN).cloned();
+            let mut resp = Response::builder()
+                .status(StatusCode::SWITCHING_PROTOCOLS)
+                .header(CONNECTION, "Upgrade")
+                .header(UPGRADE, "websocket")
+                .header("Sec-WebSocket-Accept", "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=")
+                .body(Full::new(Bytes::new()))
+                .unwrap();
+            if let Some(seen_origin) = seen_origin {
+                resp.headers_mut().insert("Seen-Origin", seen_origin);
+            }
+            resp
+        }
+
+        async fn proxy_to_origin() -> (TheInsecureProxy, u16) {
+            let (port, _) = start_origin_with(origin).await;
+            let resolver = DnsResolver::new(
+                vec![("localhost".parse().unwrap(), "127.0.0.1".parse().unwrap())],
+                vec![],
+                IpPreference::Any,
+                DEFAULT_CACHE_TTL,
+            );
+            let destination_policy = Arc::new(DestinationPolicy::new(vec![], vec![], vec![]));
+            let upstream_tls =
+                UpstreamTls::new(vec![], vec!["localhost".parse().unwrap()], None, None);
+            let (client, websocket_client) =
+                make_clients(&resolver, &destination_policy, &None, &upstream_tls).unwrap();
+            let proxy = TheInsecureProxy {
+                client,
+                websocket_client,
+                destination_policy,
+                resolver,
+                ..make_proxy()
+            };
+            (proxy, port)
+        }
+
+        fn connection_from(client_addr: SocketAddr) -> Connection {
+            let address = ListenAddress::Tcp("127.0.0.1:3080".parse().unwrap());
+            Connection {
+                client_addr,
+                original_dst: None,
+                listener: Arc::new(Listener::new("test", address)),
+            }
+        }
+
+        async fn send(method: Method, path: &str) -> (response::Parts, Bytes) {
+            let (proxy, port) = proxy_to_origin().await;
+            let req = Request::builder()
+                .method(method)
+                .uri(path)
+                .header(HOST, format!("localhost:{}", port))
+                .body(())
+                .unwrap();
+            let resp = proxy
+                .proxy_request(req, None, "default", &ClientProfile::default())
+                .await
+                .unwrap();
+            let (parts, body) = resp.into_parts();
+            (parts, body.collect().await.unwrap().to_bytes())
+        }
+
+        #[tokio::test]
+        async fn get_rewrites_the_body_and_its_length() {
+            let (parts, body) = send(Method::GET, "/").await;
+            assert_eq!(parts.status, StatusCode::OK);
+            assert!(body.starts_with(b"<a href=\"http://"), "{:?}", body);
+            assert_eq!(parts.headers[CONTENT_LENGTH], HeaderValue::from(body.len()));
+            assert_ne!(parts.headers[ETAG], "\"origin\"");
+        }
+
+        #[tokio::test]
+        async fn head_keeps_the_origins_headers() {
+            let (parts, body) = send(Method::HEAD, "/").await;
+            assert_eq!(parts.status, StatusCode::OK);
+            assert!(body.is_empty());
+            assert_eq!(parts.headers[CONTENT_LENGTH], HeaderValue::from(PAGE.len()));
+            assert_eq!(parts.headers[ETAG], "\"origin\"");
+        }
+
+        #[tokio::test]
+        async fn no_content_stays_empty() {
+            let (parts, body) = send(Method::GET, "/empty").await;
+            assert_eq!(parts.status, StatusCode::NO_CONTENT);
+            assert!(body.is_empty());
+            assert!(!parts.headers.contains_key(CONTENT_LENGTH));
+            assert_eq!(parts.headers[ETAG], "\"origin\"");
+        }
+
+        #[tokio::test]
+        async fn websockets_are_passed_through() {
+            let (proxy, origin_port) = proxy_to_origin().await;
+            let proxy = Arc::new(proxy);
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let proxy_addr = listener.local_addr().unwrap();
+            tokio::task::spawn(async move {
+                let (stream, client_addr) = listener.accept().await.unwrap();
+                let connection = connection_from(client_addr);
+                let service = service_fn(move |req| {
+                    let proxy = proxy.clone();
+                    let connection = connection.clone();
+                    async move {
+                        proxy
+                            .proxy_websocket(req, &connection)
+                            .await
+                            .map_err(|err| err.to_string())
+                    }
+                });
+                http1::Builder::new()
+                    .serve_connection(TokioIo::new(stream), service)
+                    .with_upgrades()
+                    .await
+                    .unwrap();
+            });
+
+            let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+            let request = format!(
+                "GET /socket HTTP/1.1\r\nHost: localhost:{0}\r\n\
+                 Connection: Upgrade\r\nUpgrade: websocket\r\n\
+                 Origin: http://localhost:{0}\r\n\
+                 Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                 Sec-WebSocket-Version: 13\r\n\r\n",
+                origin_port
+            );
+            client.write_all(request.as_bytes()).await.unwrap();
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                let mut byte = [0u8; 1];
+                assert_eq!(client.read(&mut byte).await.unwrap(), 1);
+                head.push(byte[0]);
+            }
+            let head = String::from_utf8(head).unwrap().to_ascii_lowercase();
+            assert!(head.starts_with("http/1.1 101"), "{}", head);
+            assert!(head.contains("sec-websocket-accept: s3pplmbitxaq9kygzzhzrbk+xoo="));
+            let seen_origin = format!("seen-origin: https://localhost:{}", origin_port);
+            assert!(head.contains(&seen_origin), "{}", head);
+
+            // a masked text frame saying hello, which comes back as it went
+            let frame = b"\x81\x85\x37\xfa\x21\x3d\x7f\x9f\x4d\x51\x58";
+            client.write_all(frame).await.unwrap();
+            let mut echoed = [0u8; 11];
+            client.read_exact(&mut echoed).await.unwrap();
+            assert_eq!(&echoed, frame);
+        }
+
+        #[tokio::test]
+        async fn hop_by_hop_headers_stop_here() {
+            let (mut proxy, port) = proxy_to_origin().await;
+            proxy.via = Via::new(Some("test-proxy".to_string())).unwrap();
+            let req = Request::get("/headers")
+                .header(HOST, format!("localhost:{}", port))
+                .header("Connection", "keep-alive, X-Secret")
+                .header("Keep-Alive", "timeout=5")
+                .header("X-Secret", "for the proxy")
+                .header("X-Kept", "for the origin")
+                .body(())
+                .unwrap();
+            let resp = proxy
+                .proxy_request(req, None, "default", &ClientProfile::default())
+                .await
+                .unwrap();
+            // the origin speaks h2 to us
+            assert_eq!(resp.headers()["Via"], "2 test-proxy");
+            let body = resp.into_body().collect().await.unwrap().to_bytes();
+            let seen = String::from_utf8(body.to_vec()).unwrap();
+            assert!(seen.contains("x-kept: for the origin\n"), "{}", seen);
+            assert!(seen.contains("via: 1.1 test-proxy\n"), "{}", seen);
+            assert!(!seen.contains("x-secret"), "{}", seen);
+            assert!(!seen.contains("keep-alive"), "{}", seen);
+        }
+
+        #[tokio::test]
+        async fn origins_are_told_who_the_client_is() {
+            let (mut proxy, port) = proxy_to_origin().await;
+            proxy.forwarded = ForwardedHeaders::new(Style::XForwarded, Existing::Strip, vec![]);
+            let req = Request::get("/headers")
+                .header(HOST, format!("localhost:{}", port))
+                .header("X-Forwarded-For", "10.9.9.9")
+                .body(())
+                .unwrap();
+            let connection = connection_from("192.0.2.1:50000".parse().unwrap());
+            let resp = proxy
+                .proxy_request(req, Some(&connection), "default", &ClientProfile::default())
+                .await
+                .unwrap();
+            let body = resp.into_body().collect().await.unwrap().to_bytes();
+            let seen = String::from_utf8(body.to_vec()).unwrap();
+            assert!(seen.contains("x-forwarded-for: 192.0.2.1\n"), "{}", seen);
+            assert!(seen.contains("x-forwarded-proto: http\n"), "{}", seen);
+            let host = format!("x-forwarded-host: localhost:{}\n", port);
+            assert!(seen.contains(&host), "{}", seen);
+        }
+
+        #[tokio::test]
+        async fn not_modified_keeps_its_headers() {
+            let (parts, body) = send(Method::GET, "/unchanged").await;
+            assert_eq!(parts.status, StatusCode::NOT_MODIFIED);
+            assert!(body.is_empty());
+            assert!(!parts.headers.contains_key(CONTENT_LENGTH));
+            assert_eq!(parts.headers[ETAG], "\"origin\"");
+            assert_eq!(parts.headers["Cache-Control"], "no-store");
+        }
+    }
 }
@@ -0,0 +1,408 @@
+use crate::config::env_string;
+
+use openssl::asn1::{Asn1Integer, Asn1Time};
+use openssl::bn::{BigNum, MsbOption};
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::provider::Provider;
+use openssl::rsa::Rsa;
+use openssl::ssl::{NameType, SniError, Ssl, SslContext, SslContextBuilder, SslMethod, SslOptions};
+use openssl::x509::extension::{BasicConstraints, ExtendedKeyUsage, SubjectAlternativeName};
+use openssl::x509::{X509NameBuilder, X509};
+use std::collections::HashMap;
+use std::error::Error;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::runtime::{Handle, RuntimeFlavor};
+use tokio_openssl::SslStream;
+
+// the name on the certificate we hand to clients that don't send SNI, which
+// is most of the ones old enough to need this
+pub const DEFAULT_INTERCEPT_HOST: &str = "the-insecure-proxy";
+
+const LEAF_KEY_BITS: u32 = 2048;
+const LEAF_VALIDITY_DAYS: u32 = 365;
+// every hostname a client asks for gets a certificate, so only this many are
+// kept at once
+const MAX_LEAVES: usize = 1024;
+
+// A certificate and key for one hostname, signed by our CA
+pub struct Leaf {
+    pub cert: X509,
+    pub key: PKey<Private>,
+}
+
+// The locally-trusted CA we mint certificates from. Certificates are made the
+// first time a hostname is seen and kept until there are too many. They all
+// share one key, made at startup, so minting one is just signing it.
+pub struct CertificateAuthority {
+    cert: X509,
+    key: PKey<Private>,
+    // Windows 98-era clients can't check SHA-256 signatures
+    digest: MessageDigest,
+    leaf_key: PKey<Private>,
+    leaves: Mutex<HashMap<String, Arc<Leaf>>>,
+}
+
+impl CertificateAuthority {
+    pub fn new(
+        cert: X509,
+        key: PKey<Private>,
+        digest: MessageDigest,
+    ) -> Result<CertificateAuthority, ErrorStack> {
+        Ok(CertificateAuthority {
+            cert,
+            key,
+            digest,
+            leaf_key: PKey::from_rsa(Rsa::generate(LEAF_KEY_BITS)?)?,
+            leaves: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn leaf_for(&self, host: &str) -> Result<Arc<Leaf>, ErrorStack> {
+        let host = host.to_ascii_lowercase();
+        if let Some(leaf) = self.leaves.lock().unwrap().get(&host) {
+            return Ok(leaf.clone());
+        }
+
+        // signed without the lock so one new host doesn't hold up the rest
+        let leaf = Arc::new(self.mint(&host)?);
+        let mut leaves = self.leaves.lock().unwrap();
+        if let Some(leaf) = leaves.get(&host) {
+            // another handshake for the same host got there first
+            return Ok(leaf.clone());
+        }
+        if leaves.len() >= MAX_LEAVES {
+            leaves.clear();
+        }
+        leaves.insert(host, leaf.clone());
+        Ok(leaf)
+    }
+
+    fn mint(&self, host: &str) -> Result<Leaf, ErrorStack> {
+        let key = self.leaf_key.clone();
+
+        let mut name = X509NameBuilder::new()?;
+        // CNs are limited to 64 characters - longer names only go in the SAN
+        if host.len() <= 64 {
+            name.append_entry_by_nid(Nid::COMMONNAME, host)?;
+        }
+        let name = name.build();
+
+        let mut builder = X509::builder()?;
+        builder.set_version(2)?;
+        let serial = random_serial()?;
+        builder.set_serial_number(&serial)?;
+        builder.set_subject_name(&name)?;
+        builder.set_issuer_name(self.cert.subject_name())?;
+        builder.set_pubkey(&key)?;
+        // backdated a day in case the client's clock is a bit behind
+        let not_before = Asn1Time::from_unix(unix_now() - 86400)?;
+        let not_after = Asn1Time::days_from_now(LEAF_VALIDITY_DAYS)?;
+        builder.set_not_before(&not_before)?;
+        builder.set_not_after(&not_after)?;
+
+        let mut san = SubjectAlternativeName::new();
+        if host.parse::<IpAddr>().is_ok() {
+            san.ip(host);
+        } else {
+            san.dns(host);
+        }
+        let san = san.build(&builder.x509v3_context(Some(&self.cert), None))?;
+        builder.append_extension(san)?;
+        builder.append_extension(BasicConstraints::new().build()?)?;
+        builder.append_extension(ExtendedKeyUsage::new().server_auth().build()?)?;
+        builder.sign(&self.key, self.digest)?;
+
+        Ok(Leaf {
+            cert: builder.build(),
+            key,
+        })
+    }
+}
+
+// Terminates TLS from legacy clients - SSL 3.0 (if this OpenSSL still has
+// it), TLS 1.0 and whatever weak ciphers they offer - presenting a
+// certificate for whichever host they asked for via SNI.
+pub struct TlsInterception {
+    context: SslContext,
+}
+
+impl TlsInterception {
+    pub fn new(
+        ca: CertificateAuthority,
+        default_host: &str,
+    ) -> Result<TlsInterception, ErrorStack> {
+        let mut builder = SslContextBuilder::new(SslMethod::tls_server())?;
+        builder.set_options(SslOptions::ALL);
+        builder.set_security_level(0);
+        builder.set_min_proto_version(None)?;
+        builder.set_cipher_list("ALL:@SECLEVEL=0")?;
+
+        let default_leaf = ca.leaf_for(default_host)?;
+        builder.set_certificate(&default_leaf.cert)?;
+        builder.set_private_key(&default_leaf.key)?;
+
+        builder.set_servername_callback(move |ssl, _alert| {
+            let host = match ssl.servername(NameType::HOST_NAME) {
+                Some(host) => host.to_string(),
+                None => return Ok(()),
+            };
+            let leaf = off_the_runtime(|| ca.leaf_for(&host)).map_err(|err| {
+                println!("= failed to make a certificate for {}: {}", host, err);
+                SniError::ALERT_FATAL
+            })?;
+            ssl.set_certificate(&leaf.cert)
+                .and_then(|_| ssl.set_private_key(&leaf.key))
+                .map_err(|_| SniError::ALERT_FATAL)
+        });
+
+        Ok(TlsInterception {
+            context: builder.build(),
+        })
+    }
+
+    // None unless TLS_INTERCEPT_CA_CERT and TLS_INTERCEPT_CA_KEY are set
+    pub fn from_env() -> Result<Option<TlsInterception>, Box<dyn Error>> {
+        let (cert_path, key_path) = match (
+            env_string("TLS_INTERCEPT_CA_CERT")?,
+            env_string("TLS_INTERCEPT_CA_KEY")?,
+        ) {
+            (Some(cert), Some(key)) => (cert, key),
+            (None, None) => return Ok(None),
+            _ => {
+                return Err(
+                    "TLS_INTERCEPT_CA_CERT and TLS_INTERCEPT_CA_KEY must be set together".into(),
+                )
+            }
+        };
+
+        let cert = X509::from_pem(&std::fs::read(&cert_path)?)
+            .map_err(|err| format!("couldn't read CA certificate {}: {}", cert_path, err))?;
+        let key = PKey::private_key_from_pem(&std::fs::read(&key_path)?)
+            .map_err(|err| format!("couldn't read CA key {}: {}", key_path, err))?;
+        let digest = match env_string("TLS_INTERCEPT_DIGEST")?.as_deref() {
+            None | Some("sha1") => MessageDigest::sha1(),
+            Some("sha256") => MessageDigest::sha256(),
+            Some(_) => return Err("TLS_INTERCEPT_DIGEST should be sha1 or sha256".into()),
+        };
+        let default_host = env_string("TLS_INTERCEPT_DEFAULT_HOST")?
+            .unwrap_or_else(|| DEFAULT_INTERCEPT_HOST.to_string());
+
+        load_legacy_provider();
+        let ca = CertificateAuthority::new(cert, key, digest)?;
+        Ok(Some(TlsInterception::new(ca, &default_host)?))
+    }
+
+    pub async fn accept<S>(&self, stream: S) -> Result<SslStream<S>, Box<dyn Error + Send + Sync>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut stream = SslStream::new(Ssl::new(&self.context)?, stream)?;
+        Pin::new(&mut stream).accept().await?;
+        Ok(stream)
+    }
+}
+
+// The SNI callback runs in the middle of an async handshake, so signing a
+// new certificate would otherwise stall every other connection on the same
+// worker thread. block_in_place hands those to another worker while it runs.
+// A single-threaded runtime has nowhere to hand them, so there it just runs.
+fn off_the_runtime<T>(work: impl FnOnce() -> T) -> T {
+    match Handle::try_current() {
+        Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+            tokio::task::block_in_place(work)
+        }
+        _ => work(),
+    }
+}
+
+// RC4 and friends live in OpenSSL 3's legacy provider. Loading it explicitly
+// means the default provider has to be loaded explicitly too. Providers stay
+// loaded until the process exits, so there's nothing to hold on to.
+fn load_legacy_provider() {
+    for name in ["default", "legacy"] {
+        match Provider::try_load(None, name, true) {
+            Ok(provider) => std::mem::forget(provider),
+            Err(err) => println!("Couldn't load OpenSSL {} provider: {}", name, err),
+        }
+    }
+}
+
+fn random_serial() -> Result<Asn1Integer, ErrorStack> {
+    let mut serial = BigNum::new()?;
+    serial.rand(128, MsbOption::MAYBE_ZERO, false)?;
+    serial.to_asn1_integer()
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use openssl::ssl::{SslConnector, SslVerifyMode};
+    use tokio::net::{TcpListener, TcpStream};
+
+    fn test_ca() -> CertificateAuthority {
+        test_ca_signing_with(MessageDigest::sha1())
+    }
+
+    // also used to stand in for origins with certificates from a private CA
+    pub(crate) fn test_ca_signing_with(digest: MessageDigest) -> CertificateAuthority {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, "Test CA")
+            .unwrap();
+        let name = name.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder
+            .set_serial_number(&random_serial().unwrap())
+            .unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        builder
+            .append_extension(BasicConstraints::new().ca().build().unwrap())
+            .unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+
+        CertificateAuthority::new(builder.build(), key, digest).unwrap()
+    }
+
+    pub(crate) fn ca_pem(ca: &CertificateAuthority) -> Vec<u8> {
+        ca.cert.to_pem().unwrap()
+    }
+
+    fn common_name(cert: &X509) -> String {
+        cert.subject_name()
+            .entries_by_nid(Nid::COMMONNAME)
+            .next()
+            .unwrap()
+            .data()
+            .as_utf8()
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn minted_leaf_is_signed_by_ca() {
+        let ca = test_ca();
+        let leaf = ca.leaf_for("www.example.com").unwrap();
+
+        let ca_key = ca.cert.public_key().unwrap();
+        assert!(leaf.cert.verify(&ca_key).unwrap());
+        assert_eq!(common_name(&leaf.cert), "www.example.com");
+        assert_eq!(
+            leaf.cert
+                .subject_alt_names()
+                .unwrap()
+                .get(0)
+                .unwrap()
+                .dnsname(),
+            Some("www.example.com")
+        );
+    }
+
+    #[test]
+    fn minted_leaf_for_ip_address_has_ip_san() {
+        let leaf = test_ca().leaf_for("192.0.2.1").unwrap();
+        assert_eq!(
+            leaf.cert
+                .subject_alt_names()
+                .unwrap()
+                .get(0)
+                .unwrap()
+                .ipaddress(),
+            Some(&[192, 0, 2, 1][..])
+        );
+    }
+
+    #[test]
+    fn leaves_are_cached_per_host() {
+        let ca = test_ca();
+        let first = ca.leaf_for("example.com").unwrap();
+        let second = ca.leaf_for("EXAMPLE.com").unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+    }
+
+    #[test]
+    fn leaves_share_one_key() {
+        let ca = test_ca();
+        let first = ca.leaf_for("one.example.com").unwrap();
+        let second = ca.leaf_for("two.example.com").unwrap();
+        assert!(first.key.public_eq(&second.key));
+        assert!(first.cert.public_key().unwrap().public_eq(&ca.leaf_key));
+    }
+
+    #[test]
+    fn leaf_cache_is_capped() {
+        let ca = test_ca();
+        for n in 0..MAX_LEAVES + 1 {
+            ca.leaf_for(&format!("host{}.example.com", n)).unwrap();
+        }
+        assert!(ca.leaves.lock().unwrap().len() <= MAX_LEAVES);
+    }
+
+    async fn handshake(interception: TlsInterception, sni: Option<&str>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::task::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let _ = interception.accept(stream).await;
+        });
+
+        let mut connector = SslConnector::builder(SslMethod::tls_client()).unwrap();
+        connector.set_verify(SslVerifyMode::NONE);
+        let mut config = connector.build().configure().unwrap();
+        config.set_use_server_name_indication(sni.is_some());
+        config.set_verify_hostname(false);
+        let ssl = config.into_ssl(sni.unwrap_or("unused")).unwrap();
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut stream = SslStream::new(ssl, stream).unwrap();
+        Pin::new(&mut stream).connect().await.unwrap();
+        common_name(&stream.ssl().peer_certificate().unwrap())
+    }
+
+    #[tokio::test]
+    async fn handshake_presents_certificate_for_sni_host() {
+        let interception = TlsInterception::new(test_ca(), DEFAULT_INTERCEPT_HOST).unwrap();
+        assert_eq!(
+            handshake(interception, Some("old.example.com")).await,
+            "old.example.com"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn handshake_mints_off_the_runtime() {
+        let interception = TlsInterception::new(test_ca(), DEFAULT_INTERCEPT_HOST).unwrap();
+        assert_eq!(
+            handshake(interception, Some("new.example.com")).await,
+            "new.example.com"
+        );
+    }
+
+    #[tokio::test]
+    async fn handshake_without_sni_presents_default_certificate() {
+        let interception = TlsInterception::new(test_ca(), "proxy.lan").unwrap();
+        assert_eq!(handshake(interception, None).await, "proxy.lan");
+    }
+}
@@ -0,0 +1,625 @@
+use crate::config::{env_list, env_parse, env_string};
+use crate::host_pattern::{self, HostPattern};
+use crate::upstream_proxy::UpstreamConnector;
+
+use hyper::http::Uri;
+use hyper_util::rt::TokioIo;
+use std::error::Error;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::net::TcpStream;
+use tower_service::Service;
+
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+use hyper_tls::native_tls::{self, Certificate, Identity, Protocol};
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+use hyper_tls::{HttpsConnecting, HttpsConnector, MaybeHttpsStream};
+
+#[cfg(feature = "rustls")]
+use hyper_rustls::{HttpsConnector, MaybeHttpsStream};
+#[cfg(feature = "rustls")]
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+#[cfg(feature = "rustls")]
+use rustls::crypto::CryptoProvider;
+#[cfg(feature = "rustls")]
+use rustls::pki_types::pem::PemObject;
+#[cfg(feature = "rustls")]
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+#[cfg(feature = "rustls")]
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, SignatureScheme};
+
+#[cfg(not(any(feature = "native-tls", feature = "rustls")))]
+compile_error!("enable the native-tls or rustls feature for upstream TLS");
+
+const PEM_CERTIFICATE_START: &str = "-----BEGIN CERTIFICATE-----";
+const PEM_CERTIFICATE_END: &str = "-----END CERTIFICATE-----";
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum TlsVersion {
+    Tls10,
+    Tls11,
+    Tls12,
+    Tls13,
+}
+
+impl FromStr for TlsVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim().to_ascii_lowercase();
+        match s.strip_prefix("tls").unwrap_or(&s) {
+            "1.0" => Ok(TlsVersion::Tls10),
+            "1.1" => Ok(TlsVersion::Tls11),
+            "1.2" => Ok(TlsVersion::Tls12),
+            "1.3" => Ok(TlsVersion::Tls13),
+            _ => Err(format!("unknown TLS version {:?}", s)),
+        }
+    }
+}
+
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+impl TlsVersion {
+    fn protocol(self) -> Protocol {
+        match self {
+            TlsVersion::Tls10 => Protocol::Tlsv10,
+            TlsVersion::Tls11 => Protocol::Tlsv11,
+            TlsVersion::Tls12 => Protocol::Tlsv12,
+            TlsVersion::Tls13 => Protocol::Tlsv13,
+        }
+    }
+}
+
+// Where the CAs we trust by default come from
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub enum TrustedRoots {
+    // the operating system's store
+    #[default]
+    System,
+    // Mozilla's list, built in - for rustls builds on machines without a
+    // CA store
+    Webpki,
+}
+
+impl FromStr for TrustedRoots {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "system" => Ok(TrustedRoots::System),
+            "webpki" => Ok(TrustedRoots::Webpki),
+            other => Err(format!("unknown trusted roots {:?}", other)),
+        }
+    }
+}
+
+// How we check the origins we fetch from over TLS, on top of the trusted
+// CAs
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct UpstreamTls {
+    roots: TrustedRoots,
+    // PEM, one certificate each
+    ca_certs: Vec<String>,
+    // origins whose certificates aren't checked at all
+    insecure_hosts: Vec<HostPattern>,
+    // origins that are only offered HTTP/1.1, for servers whose HTTP/2 is
+    // broken
+    http1_hosts: Vec<HostPattern>,
+    min_version: Option<TlsVersion>,
+    // PEM certificate chain and PKCS#8 key, for origins that want to know
+    // who we are
+    client_identity: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+impl UpstreamTls {
+    pub fn new(
+        ca_certs: Vec<String>,
+        insecure_hosts: Vec<HostPattern>,
+        min_version: Option<TlsVersion>,
+        client_identity: Option<(Vec<u8>, Vec<u8>)>,
+    ) -> UpstreamTls {
+        UpstreamTls {
+            roots: TrustedRoots::System,
+            ca_certs,
+            insecure_hosts,
+            http1_hosts: vec![],
+            min_version,
+            client_identity,
+        }
+    }
+    pub fn from_env() -> Result<UpstreamTls, Box<dyn Error>> {
+        let mut ca_certs = vec![];
+        for path in env_list("UPSTREAM_CA_BUNDLES")? {
+            let bundle = std::fs::read_to_string(&path)
+                .map_err(|err| format!("couldn't read CA bundle {}: {}", path, err))?;
+            let certs = split_pem_certificates(&bundle);
+            if certs.is_empty() {
+                return Err(format!("CA bundle {} has no certificates in it", path).into());
+            }
+            ca_certs.extend(certs);
+        }
+
+        let insecure_hosts = env_list("UPSTREAM_INSECURE_HOSTS")?
+            .iter()
+            .map(|host| host.parse())
+            .collect::<Result<Vec<HostPattern>, String>>()
+            .map_err(|err| format!("UPSTREAM_INSECURE_HOSTS was not valid: {}", err))?;
+
+        let http1_hosts = env_list("UPSTREAM_HTTP1_ONLY_HOSTS")?
+            .iter()
+            .map(|host| host.parse())
+            .collect::<Result<Vec<HostPattern>, String>>()
+            .map_err(|err| format!("UPSTREAM_HTTP1_ONLY_HOSTS was not valid: {}", err))?;
+
+        let client_identity = match (
+            env_string("UPSTREAM_CLIENT_CERT")?,
+            env_string("UPSTREAM_CLIENT_KEY")?,
+        ) {
+            (Some(cert), Some(key)) => Some((
+                std::fs::read(&cert)
+                    .map_err(|err| format!("couldn't read client certificate {}: {}", cert, err))?,
+                std::fs::read(&key)
+                    .map_err(|err| format!("couldn't read client key {}: {}", key, err))?,
+            )),
+            (None, None) => None,
+            _ => {
+                return Err(
+                    "UPSTREAM_CLIENT_CERT and UPSTREAM_CLIENT_KEY must be set together".into(),
+                )
+            }
+        };
+
+        Ok(UpstreamTls {
+            roots: env_parse("UPSTREAM_TLS_ROOTS")?.unwrap_or_default(),
+            http1_hosts,
+            ..UpstreamTls::new(
+                ca_certs,
+                insecure_hosts,
+                env_parse("UPSTREAM_MIN_TLS_VERSION")?,
+                client_identity,
+            )
+        })
+    }
+
+    pub fn connector(
+        &self,
+        http: UpstreamConnector,
+    ) -> Result<UpstreamTlsConnector, Box<dyn Error>> {
+        let connector = |insecure, http2| -> Result<_, Box<dyn Error>> {
+            let config = self.backend_config(insecure, http2)?;
+            Ok(HttpsConnector::from((http.clone(), config)))
+        };
+        Ok(UpstreamTlsConnector {
+            connectors: [
+                [connector(false, false)?, connector(false, true)?],
+                [connector(true, false)?, connector(true, true)?],
+            ],
+            insecure_hosts: Arc::new(self.insecure_hosts.clone()),
+            http1_hosts: Arc::new(self.http1_hosts.clone()),
+        })
+    }
+
+    #[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+    fn backend_config(
+        &self,
+        insecure: bool,
+        http2: bool,
+    ) -> Result<tokio_native_tls::TlsConnector, Box<dyn Error>> {
+        if self.roots != TrustedRoots::System {
+            return Err("UPSTREAM_TLS_ROOTS=webpki needs a build with the rustls feature".into());
+        }
+        let mut builder = native_tls::TlsConnector::builder();
+        for cert in &self.ca_certs {
+            builder.add_root_certificate(Certificate::from_pem(cert.as_bytes())?);
+        }
+        builder.min_protocol_version(self.min_version.map(TlsVersion::protocol));
+        if let Some((cert, key)) = &self.client_identity {
+            let identity = Identity::from_pkcs8(cert, key)
+                .map_err(|err| format!("couldn't load the client certificate: {}", err))?;
+            builder.identity(identity);
+        }
+        if insecure {
+            builder.danger_accept_invalid_certs(true);
+            builder.danger_accept_invalid_hostnames(true);
+        }
+        if http2 {
+            builder.request_alpns(&["h2", "http/1.1"]);
+        }
+        Ok(builder.build()?.into())
+    }
+
+    // rustls only speaks TLS 1.2 and 1.3, so lower minimums change nothing
+    #[cfg(feature = "rustls")]
+    fn backend_config(
+        &self,
+        insecure: bool,
+        http2: bool,
+    ) -> Result<Arc<ClientConfig>, Box<dyn Error>> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let versions: &[&rustls::SupportedProtocolVersion] = match self.min_version {
+            Some(TlsVersion::Tls13) => &[&rustls::version::TLS13],
+            _ => &[&rustls::version::TLS12, &rustls::version::TLS13],
+        };
+        let builder = ClientConfig::builder_with_provider(provider.clone())
+            .with_protocol_versions(versions)?;
+
+        let builder = if insecure {
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(AcceptAnyCertificate(provider)))
+        } else {
+            let mut roots = RootCertStore::empty();
+            match self.roots {
+                TrustedRoots::System => {
+                    let native = rustls_native_certs::load_native_certs();
+                    for err in &native.errors {
+                        println!("= couldn't load a system CA certificate: {}", err);
+                    }
+                    roots.add_parsable_certificates(native.certs);
+                }
+                TrustedRoots::Webpki => {
+                    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+                }
+            }
+            for cert in &self.ca_certs {
+                roots.add(CertificateDer::from_pem_slice(cert.as_bytes())?)?;
+            }
+            builder.with_root_certificates(roots)
+        };
+
+        let mut config = match &self.client_identity {
+            Some((cert, key)) => {
+                let chain = CertificateDer::pem_slice_iter(cert)
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(|err| format!("couldn't load the client certificate: {}", err))?;
+                let key = PrivateKeyDer::from_pem_slice(key)
+                    .map_err(|err| format!("couldn't load the client key: {}", err))?;
+                builder.with_client_auth_cert(chain, key)?
+            }
+            None => builder.with_no_client_auth(),
+        };
+        if http2 {
+            config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        }
+        Ok(Arc::new(config))
+    }
+}
+
+// For UPSTREAM_INSECURE_HOSTS: takes any certificate at all, though the
+// handshake signatures still have to add up
+#[cfg(feature = "rustls")]
+#[derive(Debug)]
+struct AcceptAnyCertificate(Arc<CryptoProvider>);
+
+#[cfg(feature = "rustls")]
+impl ServerCertVerifier for AcceptAnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+fn split_pem_certificates(bundle: &str) -> Vec<String> {
+    let mut certs = vec![];
+    let mut rest = bundle;
+    while let Some(start) = rest.find(PEM_CERTIFICATE_START) {
+        let Some(len) = rest[start..].find(PEM_CERTIFICATE_END) else {
+            break;
+        };
+        let end = start + len + PEM_CERTIFICATE_END.len();
+        certs.push(format!("{}\n", &rest[start..end]));
+        rest = &rest[end..];
+    }
+    certs
+}
+
+// Describes a failed TLS handshake with an origin, if that's why err
+// happened, so we can tell the user rather than just hanging up on them.
+pub fn describe_error(err: &(dyn Error + 'static)) -> Option<String> {
+    let mut source = Some(err);
+    while let Some(err) = source {
+        if let Some(tls_err) = tls_error(err) {
+            return Some(tls_err.to_string());
+        }
+        source = err.source();
+    }
+    None
+}
+
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+fn tls_error<'a>(err: &'a (dyn Error + 'static)) -> Option<&'a (dyn Error + 'static)> {
+    err.downcast_ref::<native_tls::Error>()
+        .map(|err| err as &(dyn Error + 'static))
+}
+
+// hyper-rustls hands back rustls errors wrapped in io::Errors (sometimes
+// twice), whose source() skips straight past them
+#[cfg(feature = "rustls")]
+fn tls_error<'a>(mut err: &'a (dyn Error + 'static)) -> Option<&'a (dyn Error + 'static)> {
+    while let Some(io_err) = err.downcast_ref::<std::io::Error>() {
+        err = io_err.get_ref()?;
+        if err.is::<rustls::Error>() {
+            return Some(err);
+        }
+    }
+    None
+}
+
+// The TLS connector under the upstream client. Origins in
+// UPSTREAM_INSECURE_HOSTS get a connector that doesn't check certificates,
+// and origins in UPSTREAM_HTTP1_ONLY_HOSTS one that doesn't offer h2 over
+// ALPN; everything else gets the normal one. The client speaks whichever
+// protocol the origin picks.
+#[derive(Clone)]
+pub struct UpstreamTlsConnector {
+    // indexed by [insecure][http2]
+    connectors: [[HttpsConnector<UpstreamConnector>; 2]; 2],
+    insecure_hosts: Arc<Vec<HostPattern>>,
+    http1_hosts: Arc<Vec<HostPattern>>,
+}
+
+impl UpstreamTlsConnector {
+    // the same connector, never offering h2 - upgrading a connection to a
+    // WebSocket needs HTTP/1.1
+    pub fn http1_only(mut self) -> UpstreamTlsConnector {
+        for connectors in &mut self.connectors {
+            connectors[1] = connectors[0].clone();
+        }
+        self
+    }
+}
+
+#[cfg(all(feature = "native-tls", not(feature = "rustls")))]
+type HttpsFuture = HttpsConnecting<TokioIo<TcpStream>>;
+#[cfg(feature = "rustls")]
+type HttpsFuture = <HttpsConnector<UpstreamConnector> as Service<Uri>>::Future;
+
+impl Service<Uri> for UpstreamTlsConnector {
+    type Response = MaybeHttpsStream<TokioIo<TcpStream>>;
+    type Error = Box<dyn Error + Send + Sync>;
+    type Future = HttpsFuture;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        for connector in self.connectors.iter_mut().flatten() {
+            match connector.poll_ready(cx) {
+                Poll::Ready(Ok(())) => {}
+                other => return other,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let host = uri.host().unwrap_or_default();
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        let insecure = host_pattern::any_match(&self.insecure_hosts, host);
+        let http2 = !host_pattern::any_match(&self.http1_hosts, host);
+        self.connectors[insecure as usize][http2 as usize].call(uri)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_tls_versions() {
+        assert_eq!("1.2".parse(), Ok(TlsVersion::Tls12));
+        assert_eq!("TLS1.3".parse(), Ok(TlsVersion::Tls13));
+        assert!("1.4".parse::<TlsVersion>().is_err());
+    }
+
+    #[test]
+    fn split_bundles_into_certificates() {
+        let bundle = format!(
+            "# lab CA\n{0}\nAAAA\n{1}\n{0}\nBBBB\n{1}\n",
+            PEM_CERTIFICATE_START, PEM_CERTIFICATE_END
+        );
+        let certs = split_pem_certificates(&bundle);
+        assert_eq!(certs.len(), 2);
+        assert!(certs[1].contains("BBBB"));
+        assert!(!certs[0].contains("lab CA"));
+    }
+
+    #[test]
+    fn webpki_roots() {
+        assert_eq!("webpki".parse(), Ok(TrustedRoots::Webpki));
+        let tls = UpstreamTls {
+            roots: TrustedRoots::Webpki,
+            ..UpstreamTls::default()
+        };
+        assert_eq!(
+            tls.backend_config(false, true).is_ok(),
+            cfg!(feature = "rustls")
+        );
+    }
+
+    // handshakes with a stand-in origin, which borrows the TLS interception
+    // CA to sign its certificate
+    #[cfg(feature = "tls-interception")]
+    pub(crate) mod handshakes {
+        use super::*;
+        use crate::destination_policy::{DestinationPolicy, GuardedResolver};
+        use crate::dns_resolver::{DnsResolver, IpPreference, DEFAULT_CACHE_TTL};
+        use crate::tls_interception::tests::{ca_pem, test_ca_signing_with};
+        use bytes::Bytes;
+        use http_body_util::Full;
+        use hyper::body::Incoming;
+        use hyper::server::conn::{http1, http2};
+        use hyper::service::service_fn;
+        use hyper::{Request, Response, Version};
+        use hyper_util::client::legacy::connect::HttpConnector;
+        use hyper_util::client::legacy::Client;
+        use hyper_util::rt::TokioExecutor;
+        use openssl::hash::MessageDigest;
+        use openssl::ssl::{self as openssl_ssl, AlpnError, Ssl, SslAcceptor, SslMethod};
+        use std::convert::Infallible;
+        use std::future::Future;
+        use std::pin::Pin;
+        use tokio::net::TcpListener;
+        use tokio_openssl::SslStream;
+
+        async fn start_origin() -> (u16, String) {
+            start_origin_with(|_req| async { Response::new(Full::new(Bytes::from("hello"))) }).await
+        }
+
+        // an HTTPS origin on localhost with a certificate from a private CA,
+        // speaking h2 to clients that offer it, returning the CA's
+        // certificate
+        pub(crate) async fn start_origin_with<F, R>(respond: F) -> (u16, String)
+        where
+            F: Fn(Request<Incoming>) -> R + Clone + Send + Sync + 'static,
+            R: Future<Output = Response<Full<Bytes>>> + Send + 'static,
+        {
+            let ca = test_ca_signing_with(MessageDigest::sha256());
+            let pem = String::from_utf8(ca_pem(&ca)).unwrap();
+            let leaf = ca.leaf_for("localhost").unwrap();
+            let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+            acceptor.set_certificate(&leaf.cert).unwrap();
+            acceptor.set_private_key(&leaf.key).unwrap();
+            acceptor.set_alpn_select_callback(|_ssl, offered| {
+                openssl_ssl::select_next_proto(b"\x02h2\x08http/1.1", offered)
+                    .ok_or(AlpnError::NOACK)
+            });
+            let acceptor = Arc::new(acceptor.build());
+
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            tokio::task::spawn(async move {
+                loop {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    let acceptor = acceptor.clone();
+                    let respond = respond.clone();
+                    tokio::task::spawn(async move {
+                        let ssl = Ssl::new(acceptor.context()).unwrap();
+                        let mut stream = SslStream::new(ssl, stream).unwrap();
+                        if Pin::new(&mut stream).accept().await.is_err() {
+                            return;
+                        }
+                        let h2 = stream.ssl().selected_alpn_protocol() == Some(b"h2");
+                        let service = service_fn(move |req| {
+                            let response = respond(req);
+                            async move { Ok::<_, Infallible>(response.await) }
+                        });
+                        let stream = TokioIo::new(stream);
+                        let _ = if h2 {
+                            http2::Builder::new(TokioExecutor::new())
+                                .serve_connection(stream, service)
+                                .await
+                        } else {
+                            http1::Builder::new()
+                                .serve_connection(stream, service)
+                                .with_upgrades()
+                                .await
+                        };
+                    });
+                }
+            });
+            (port, pem)
+        }
+
+        async fn get(tls: UpstreamTls, port: u16) -> Result<Response<Incoming>, String> {
+            let resolver = DnsResolver::new(
+                vec![("localhost".parse().unwrap(), "127.0.0.1".parse().unwrap())],
+                vec![],
+                IpPreference::Any,
+                DEFAULT_CACHE_TTL,
+            );
+            let policy = Arc::new(DestinationPolicy::new(vec![], vec![], vec![]));
+            let mut http = HttpConnector::new_with_resolver(GuardedResolver::new(resolver, policy));
+            http.enforce_http(false);
+            let connector = tls.connector(UpstreamConnector::new(http, None)).unwrap();
+            let client = Client::builder(TokioExecutor::new()).build(connector);
+
+            let req = Request::get(format!("https://localhost:{}/", port))
+                .body(Full::new(Bytes::new()))
+                .unwrap();
+            client
+                .request(req)
+                .await
+                .map_err(|err| describe_error(&err).unwrap_or_else(|| err.to_string()))
+        }
+
+        async fn fetch(tls: UpstreamTls, port: u16) -> Result<u16, String> {
+            get(tls, port).await.map(|res| res.status().as_u16())
+        }
+
+        #[tokio::test]
+        async fn private_ca_is_refused_by_default() {
+            let (port, _) = start_origin().await;
+            let err = fetch(UpstreamTls::default(), port).await.unwrap_err();
+            assert!(err.contains("certificate"), "{}", err);
+        }
+
+        #[tokio::test]
+        async fn extra_ca_bundle_is_trusted() {
+            let (port, pem) = start_origin().await;
+            let tls = UpstreamTls::new(vec![pem], vec![], Some(TlsVersion::Tls12), None);
+            assert_eq!(fetch(tls, port).await, Ok(200));
+        }
+
+        #[tokio::test]
+        async fn insecure_hosts_are_not_checked() {
+            let (port, _) = start_origin().await;
+            let tls = UpstreamTls::new(vec![], vec!["localhost".parse().unwrap()], None, None);
+            assert_eq!(fetch(tls, port).await, Ok(200));
+        }
+
+        #[tokio::test]
+        async fn h2_is_negotiated_when_the_origin_speaks_it() {
+            let (port, pem) = start_origin().await;
+            let tls = UpstreamTls::new(vec![pem], vec![], None, None);
+            let res = get(tls, port).await.unwrap();
+            assert_eq!(res.version(), Version::HTTP_2);
+        }
+
+        #[tokio::test]
+        async fn http1_only_hosts_stay_on_http11() {
+            let (port, pem) = start_origin().await;
+            let tls = UpstreamTls {
+                http1_hosts: vec!["localhost".parse().unwrap()],
+                ..UpstreamTls::new(vec![pem], vec![], None, None)
+            };
+            let res = get(tls, port).await.unwrap();
+            assert_eq!(res.version(), Version::HTTP_11);
+        }
+    }
+}
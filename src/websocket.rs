@@ -0,0 +1,131 @@
+use crate::upstream_tls::UpstreamTlsConnector;
+
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, CONNECTION, ORIGIN, UPGRADE};
+use hyper::upgrade::OnUpgrade;
+use hyper::{Request, Response, StatusCode};
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioIo;
+use std::error::Error;
+
+// Whether a request asks to switch its connection over to a WebSocket
+pub fn is_upgrade(headers: &HeaderMap) -> bool {
+    has_token(headers, CONNECTION, "upgrade") && has_token(headers, UPGRADE, "websocket")
+}
+
+fn has_token(headers: &HeaderMap, name: HeaderName, token: &str) -> bool {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case(token))
+}
+
+// The page that opened the socket came from the https:// site as far as the
+// origin knows, and sites that check Origin turn anything else away
+pub fn secure_origin(headers: &mut HeaderMap) {
+    let Some(origin) = headers.get(ORIGIN).and_then(|value| value.to_str().ok()) else {
+        return;
+    };
+    let Some(rest) = origin.strip_prefix("http://") else {
+        return;
+    };
+    if let Ok(origin) = HeaderValue::from_str(&format!("https://{}", rest)) {
+        headers.insert(ORIGIN, origin);
+    }
+}
+
+// Asks the origin to upgrade req, already pointed at it over TLS and with
+// the client's hop-by-hop headers gone, and if it agrees, answers the
+// client's upgrade the same way and then shovels bytes both ways until
+// either end hangs up. The frames pass through untouched - only the TLS
+// around them changes. Anything but a 101 goes back as it is.
+pub async fn handle(
+    client: &Client<UpstreamTlsConnector, Full<Bytes>>,
+    client_upgrade: OnUpgrade,
+    mut req: Request<Full<Bytes>>,
+) -> Result<Response<Full<Bytes>>, Box<dyn Error>> {
+    // asking to upgrade is hop-by-hop, so it's asked again on our hop
+    let headers = req.headers_mut();
+    headers.insert(CONNECTION, HeaderValue::from_static("Upgrade"));
+    headers.insert(UPGRADE, HeaderValue::from_static("websocket"));
+    secure_origin(headers);
+    let uri = req.uri().clone();
+    let mut resp = client.request(req).await?;
+    if resp.status() != StatusCode::SWITCHING_PROTOCOLS {
+        println!(
+            "= Origin answered WebSocket upgrade for {} with {}",
+            uri,
+            resp.status()
+        );
+        let (parts, body) = resp.into_parts();
+        let body = body.collect().await?.to_bytes();
+        return Ok(Response::from_parts(parts, Full::new(body)));
+    }
+
+    let origin_upgrade = hyper::upgrade::on(&mut resp);
+    println!("= Opened WebSocket to {}", uri);
+    tokio::task::spawn(async move {
+        match tokio::try_join!(client_upgrade, origin_upgrade) {
+            Ok((client, origin)) => {
+                let mut client = TokioIo::new(client);
+                let mut origin = TokioIo::new(origin);
+                match tokio::io::copy_bidirectional(&mut client, &mut origin).await {
+                    Ok((sent, received)) => println!(
+                        "= Closed WebSocket to {} ({} bytes sent, {} received)",
+                        uri, sent, received
+                    ),
+                    Err(err) => println!("= WebSocket to {} failed: {}", uri, err),
+                }
+            }
+            Err(err) => println!("= WebSocket upgrade for {} failed: {}", uri, err),
+        }
+    });
+
+    let (parts, _body) = resp.into_parts();
+    Ok(Response::from_parts(parts, Full::new(Bytes::new())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn spots_websocket_upgrades() {
+        assert!(is_upgrade(&headers(&[
+            ("Connection", "Upgrade"),
+            ("Upgrade", "websocket"),
+        ])));
+        assert!(is_upgrade(&headers(&[
+            ("Connection", "keep-alive, Upgrade"),
+            ("Upgrade", "WebSocket"),
+        ])));
+        assert!(!is_upgrade(&headers(&[("Upgrade", "websocket")])));
+        assert!(!is_upgrade(&headers(&[
+            ("Connection", "Upgrade"),
+            ("Upgrade", "h2c"),
+        ])));
+        assert!(!is_upgrade(&HeaderMap::new()));
+    }
+
+    #[test]
+    fn origin_becomes_secure() {
+        let mut insecure = headers(&[("Origin", "http://example.com")]);
+        secure_origin(&mut insecure);
+        assert_eq!(insecure[ORIGIN], "https://example.com");
+
+        let mut opaque = headers(&[("Origin", "null")]);
+        secure_origin(&mut opaque);
+        assert_eq!(opaque[ORIGIN], "null");
+    }
+}